@@ -0,0 +1,80 @@
+use anchor_lang::prelude::*;
+use anchor_spl::{token, token_2022, token_interface::TokenAccount};
+use crate::StakingError;
+
+/// Metaplex Token Metadata program
+pub const TOKEN_METADATA_PROGRAM_ID: Pubkey = pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+
+/// Seed prefix of Metaplex metadata PDAs
+pub const METADATA_SEED: &[u8] = b"metadata";
+
+/// Metaplex account key of a v1 metadata account
+const METADATA_V1_KEY: u8 = 4;
+
+#[derive(AnchorDeserialize)]
+struct Creator {
+    _address: Pubkey,
+    _verified: bool,
+    _share: u8,
+}
+
+#[derive(AnchorDeserialize)]
+struct Collection {
+    verified: bool,
+    key: Pubkey,
+}
+
+/// Leading fields of a Metaplex metadata account, up to the collection.
+/// Later fields vary between metadata versions and are not read.
+#[derive(AnchorDeserialize)]
+struct MetadataPrefix {
+    key: u8,
+    _update_authority: Pubkey,
+    mint: Pubkey,
+    _name: String,
+    _symbol: String,
+    _uri: String,
+    _seller_fee_basis_points: u16,
+    _creators: Option<Vec<Creator>>,
+    _primary_sale_happened: bool,
+    _is_mutable: bool,
+    _edition_nonce: Option<u8>,
+    _token_standard: Option<u8>,
+    collection: Option<Collection>,
+}
+
+/// Verified collection of `mint`, read from its Metaplex metadata account.
+/// The caller checks that `metadata` is the metadata PDA of `mint`.
+pub fn verified_collection(metadata: &AccountInfo, mint: &Pubkey) -> Result<Pubkey> {
+    require!(metadata.owner == &TOKEN_METADATA_PROGRAM_ID, StakingError::InvalidNftMetadata);
+
+    let data = metadata.try_borrow_data()?;
+    let prefix = MetadataPrefix::deserialize(&mut &data[..])
+        .map_err(|_| error!(StakingError::InvalidNftMetadata))?;
+    require!(
+        prefix.key == METADATA_V1_KEY && prefix.mint == *mint,
+        StakingError::InvalidNftMetadata
+    );
+
+    match prefix.collection {
+        Some(collection) if collection.verified => Ok(collection.key),
+        _ => err!(StakingError::InvalidNftMetadata),
+    }
+}
+
+/// Whether `token_account` still holds the boosting NFT `mint` for `owner`. A closed,
+/// emptied, transferred or re-created account is stale.
+pub fn still_holds_nft(token_account: &AccountInfo, owner: &Pubkey, mint: &Pubkey) -> bool {
+    let token_program = token_account.owner;
+    if token_account.data_is_empty() || (token_program != &token::ID && token_program != &token_2022::ID) {
+        return false;
+    }
+
+    let Ok(data) = token_account.try_borrow_data() else {
+        return false;
+    };
+    match TokenAccount::try_deserialize(&mut &data[..]) {
+        Ok(account) => account.owner == *owner && account.mint == *mint && account.amount == 1,
+        Err(_) => false,
+    }
+}
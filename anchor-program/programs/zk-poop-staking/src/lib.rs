@@ -1,623 +1,4245 @@
+
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+use bytemuck::Zeroable;
+use anchor_spl::token_interface::{
+    self, Burn, CloseAccount, Mint, MintTo, TokenAccount, TokenInterface, TransferChecked,
+};
 
+pub mod boost;
+pub mod merkle;
+pub mod migration;
 pub mod security;
+use migration::{
+    StakingPoolV0, UserAccountV0, STAKING_POOL_V0_SPACE, USER_ACCOUNT_V0_SPACE,
+};
 use security::{SecurityValidator, OperationType};
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
-#[program]
-pub mod zk_poop_staking {
+// #[program] emits its IDL instruction handlers next to the program module, and those
+// still call the deprecated AccountInfo::realloc. The wrapper keeps the allow to them.
+#[allow(deprecated)]
+mod program_entry {
     use super::*;
 
-    /// Initialize the global staking pool
-    pub fn initialize_pool(
-        ctx: Context<InitializePool>,
-        bump: u8,
-        reward_rate: u64, // Base $WePee per 1000 staked per day (scaled by 1e6)
-    ) -> Result<()> {
-        let pool = &mut ctx.accounts.staking_pool;
-        pool.authority = ctx.accounts.authority.key();
-        pool.staking_mint = ctx.accounts.staking_mint.key();
-        pool.reward_mint = ctx.accounts.reward_mint.key();
-        pool.staking_vault = ctx.accounts.staking_vault.key();
-        pool.reward_vault = ctx.accounts.reward_vault.key();
-        pool.total_staked = 0;
-        pool.reward_rate = reward_rate;
-        pool.bump = bump;
-        pool.paused = false;
-
-        msg!("Staking pool initialized with reward rate: {}", reward_rate);
-        Ok(())
-    }
+    #[program]
+    pub mod zk_poop_staking {
+        use super::*;
 
-    /// Initialize a user's staking account
-    pub fn initialize_user(ctx: Context<InitializeUser>, bump: u8) -> Result<()> {
-        let user_account = &mut ctx.accounts.user_account;
-        user_account.authority = ctx.accounts.authority.key();
-        user_account.total_staked = 0;
-        user_account.pending_rewards = 0;
-        user_account.last_reward_time = Clock::get()?.unix_timestamp;
-        user_account.bump = bump;
+        /// Initialize the global staking pool
+        pub fn initialize_pool(
+            ctx: Context<InitializePool>,
+            reward_rate: u64, // Base $WePee per 1000 staked per day (scaled by 1e6)
+        ) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_init()?;
+            pool.authority = ctx.accounts.authority.key();
+            pool.staking_mint = ctx.accounts.staking_mint.key();
+            pool.reward_mint = ctx.accounts.reward_mint.key();
+            pool.staking_vault = ctx.accounts.staking_vault.key();
+            pool.reward_vault = ctx.accounts.reward_vault.key();
+            pool.total_staked = 0;
+            pool.reward_rate = reward_rate;
+            pool.bump = ctx.bumps.staking_pool;
+            pool.staking_vault_bump = ctx.bumps.staking_vault;
+            pool.reward_vault_bump = ctx.bumps.reward_vault;
+            pool.paused = 0;
+            pool.crank_interval = MIN_CRANK_INTERVAL;
+            pool.staking_decimals = ctx.accounts.staking_mint.decimals;
+            pool.min_stakes = scale_min_stakes(ctx.accounts.staking_mint.decimals)?;
+            pool.penalty_configs = [DEFAULT_PENALTY; 4];
+            pool.version = STAKING_POOL_VERSION;
 
-        msg!("User staking account initialized for: {}", ctx.accounts.authority.key());
-        Ok(())
-    }
+            msg!("Staking pool initialized with reward rate: {}", reward_rate);
+            Ok(())
+        }
 
-    /// Stake tokens with a specific lock period
-    pub fn stake(
-        ctx: Context<Stake>,
-        amount: u64,
-        lock_period: LockPeriod,
-    ) -> Result<()> {
-        let pool = &mut ctx.accounts.staking_pool;
-        let user_account = &mut ctx.accounts.user_account;
-        let clock = Clock::get()?;
+        /// Initialize a user's staking account
+        pub fn initialize_user(ctx: Context<InitializeUser>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_init()?;
+            init_user_account(
+                &mut user_account,
+                ctx.accounts.authority.key(),
+                ctx.bumps.user_account,
+                Clock::get()?.unix_timestamp,
+            );
 
-        require!(!pool.paused, StakingError::PoolPaused);
-        require!(amount > 0, StakingError::InvalidAmount);
+            msg!("User staking account initialized for: {}", ctx.accounts.authority.key());
+            Ok(())
+        }
 
-        // Security validations
-        SecurityValidator::validate_flash_loan_protection(user_account, clock.unix_timestamp)?;
-        SecurityValidator::validate_rate_limiting(user_account, clock.unix_timestamp, OperationType::Stake)?;
-        SecurityValidator::validate_account_consistency(user_account)?;
-        SecurityValidator::validate_lock_period_gaming(&user_account.stakes, lock_period, clock.unix_timestamp)?;
-        SecurityValidator::validate_sybil_protection(user_account, amount, clock.unix_timestamp)?;
-
-        // Validate minimum stake amounts
-        let min_stake = match lock_period {
-            LockPeriod::OneDay => MIN_STAKE_24H,
-            LockPeriod::OneWeek => MIN_STAKE_1W,
-            LockPeriod::ThreeMonths => MIN_STAKE_3M,
-            LockPeriod::SixMonths => MIN_STAKE_6M,
-        };
-        require!(amount >= min_stake, StakingError::BelowMinimumStake);
+        /// Admin function to create the liquid receipt mint for flexible staking. Flexible
+        /// rewards accrue into the receipt exchange rate, so the pool must pay rewards in the
+        /// staking token.
+        pub fn initialize_receipt_mint(ctx: Context<InitializeReceiptMint>) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(pool.reward_mint == pool.staking_mint, StakingError::FlexibleMintMismatch);
 
-        // Update rewards before modifying stake
-        update_user_rewards(user_account, pool, clock.unix_timestamp)?;
+            pool.receipt_mint = ctx.accounts.receipt_mint.key();
+            pool.receipt_mint_bump = ctx.bumps.receipt_mint;
+            pool.flexible_staked = 0;
+            pool.flexible_last_update = Clock::get()?.unix_timestamp;
 
-        // Calculate lock end time
-        let lock_duration = match lock_period {
-            LockPeriod::OneDay => 86400,        // 1 day
-            LockPeriod::OneWeek => 604800,      // 7 days
-            LockPeriod::ThreeMonths => 7776000, // 90 days
-            LockPeriod::SixMonths => 15552000,  // 180 days
-        };
+            msg!("Receipt mint initialized: {}", pool.receipt_mint);
+            Ok(())
+        }
 
-        // Create new stake entry
-        let stake_entry = StakeEntry {
-            amount,
-            lock_period,
-            lock_start: clock.unix_timestamp,
-            lock_end: clock.unix_timestamp + lock_duration,
-            multiplier: get_lock_multiplier(lock_period),
-            is_active: true,
-        };
+        /// Stake tokens with a specific lock period, creating the user's staking account
+        /// on their first stake
+        pub fn stake(
+            ctx: Context<Stake>,
+            amount: u64,
+            lock_period: LockPeriod,
+        ) -> Result<()> {
+            require!(
+                !ctx.accounts.staking_pool.load()?.is_allowlisted(),
+                StakingError::AllowlistProofRequired
+            );
+            process_stake(ctx, amount, lock_period, 0)
+        }
 
-        // Add stake entry (max 10 concurrent stakes per user)
-        require!(user_account.stakes.len() < 10, StakingError::TooManyStakes);
-        user_account.stakes.push(stake_entry);
+        /// Stake during an allowlisted round. The leaf is the signer's key and per-address
+        /// cap in base units (0 = uncapped), proven against the pool's allowlist root.
+        pub fn stake_allowlisted(
+            ctx: Context<Stake>,
+            amount: u64,
+            lock_period: LockPeriod,
+            max_amount: u64,
+            proof: Vec<[u8; 32]>,
+        ) -> Result<()> {
+            {
+                let pool = ctx.accounts.staking_pool.load()?;
+                require!(pool.is_allowlisted(), StakingError::AllowlistNotEnabled);
+                require!(proof.len() <= merkle::MAX_PROOF_DEPTH, StakingError::InvalidAllowlistProof);
 
-        // Transfer tokens to vault
-        let cpi_ctx = CpiContext::new(
-            ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.user_token_account.to_account_info(),
-                to: ctx.accounts.staking_vault.to_account_info(),
-                authority: ctx.accounts.authority.to_account_info(),
-            },
-        );
-        token::transfer(cpi_ctx, amount)?;
+                let leaf = merkle::leaf_hash(&ctx.accounts.authority.key(), max_amount);
+                require!(
+                    merkle::verify_proof(&proof, &pool.allowlist_root, leaf),
+                    StakingError::InvalidAllowlistProof
+                );
+            }
 
-        // Update totals
-        user_account.total_staked += amount;
-        pool.total_staked += amount;
+            process_stake(ctx, amount, lock_period, max_amount)
+        }
 
-        emit!(StakeEvent {
-            user: ctx.accounts.authority.key(),
-            amount,
-            lock_period,
-            lock_end: stake_entry.lock_end,
-            multiplier: stake_entry.multiplier,
-        });
+        /// Unstake tokens after lock period expires
+        pub fn unstake(ctx: Context<Unstake>, stake_index: u8) -> Result<()> {
+            process_unstake(ctx, stake_index, None)
+        }
 
-        msg!("Staked {} tokens with {:?} lock period", amount, lock_period);
-        Ok(())
-    }
+        /// Unstake part of an expired entry. What stays must meet the tier minimum.
+        pub fn unstake_partial(ctx: Context<Unstake>, stake_index: u8, amount: u64) -> Result<()> {
+            process_unstake(ctx, stake_index, Some(amount))
+        }
 
-    /// Unstake tokens after lock period expires
-    pub fn unstake(ctx: Context<Unstake>, stake_index: u8) -> Result<()> {
-        let pool = &mut ctx.accounts.staking_pool;
-        let user_account = &mut ctx.accounts.user_account;
-        let clock = Clock::get()?;
+        /// Unstake every expired entry in one transfer
+        pub fn unstake_all_expired(ctx: Context<Unstake>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
 
-        require!(!pool.paused, StakingError::PoolPaused);
-        require!((stake_index as usize) < user_account.stakes.len(), StakingError::InvalidStakeIndex);
+            let (amount, entries) = unstake_expired_entries(
+                &ctx.accounts.staking_pool,
+                &mut user_account,
+                &ctx.accounts.staking_vault,
+                &ctx.accounts.user_token_account,
+                &ctx.accounts.staking_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
+            require!(!entries.is_empty(), StakingError::NoExpiredStakes);
 
-        let stake = &mut user_account.stakes[stake_index as usize];
-        require!(stake.is_active, StakingError::StakeNotActive);
-        require!(clock.unix_timestamp >= stake.lock_end, StakingError::StillLocked);
+            msg!("Unstaked {} tokens from {} expired stakes", amount, entries.len());
+            emit!(BatchUnstakeEvent {
+                user: ctx.accounts.authority.key(),
+                amount,
+                rewards_claimed: 0,
+                entries,
+            });
+            Ok(())
+        }
 
-        // Update rewards before unstaking
-        update_user_rewards(user_account, pool, clock.unix_timestamp)?;
+        /// Unstake every expired entry and claim all pending rewards in one instruction.
+        /// Either part may be empty, but not both.
+        pub fn claim_and_unstake_all_expired(ctx: Context<ClaimAndUnstake>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
 
-        let amount = stake.amount;
+            let (amount, entries) = unstake_expired_entries(
+                &ctx.accounts.staking_pool,
+                &mut user_account,
+                &ctx.accounts.staking_vault,
+                &ctx.accounts.user_token_account,
+                &ctx.accounts.staking_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
 
-        // Transfer tokens back to user
-        let seeds = &[
-            STAKING_POOL_SEED.as_bytes(),
-            &[pool.bump],
-        ];
-        let signer = &[&seeds[..]];
+            require!(
+                !entries.is_empty() || user_account.pending_rewards > 0,
+                StakingError::NothingToClaimOrUnstake
+            );
 
-        let cpi_ctx = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.staking_vault.to_account_info(),
-                to: ctx.accounts.user_token_account.to_account_info(),
-                authority: ctx.accounts.staking_pool.to_account_info(),
-            },
-            signer,
-        );
-        token::transfer(cpi_ctx, amount)?;
+            // Rewards were checkpointed by the unstake, so this only pays them out
+            let rewards_claimed = if user_account.pending_rewards > 0 {
+                claim_pending_rewards(
+                    &ctx.accounts.staking_pool,
+                    &mut user_account,
+                    &ctx.accounts.reward_vault,
+                    &ctx.accounts.user_reward_account,
+                    &ctx.accounts.reward_mint,
+                    &ctx.accounts.token_program,
+                    clock.unix_timestamp,
+                )?
+            } else {
+                0
+            };
 
-        // Mark stake as inactive
-        stake.is_active = false;
+            msg!(
+                "Unstaked {} tokens from {} expired stakes and claimed {} $WePee rewards",
+                amount, entries.len(), rewards_claimed
+            );
+            emit!(BatchUnstakeEvent {
+                user: ctx.accounts.authority.key(),
+                amount,
+                rewards_claimed,
+                entries,
+            });
+            Ok(())
+        }
 
-        // Update totals
-        user_account.total_staked -= amount;
-        pool.total_staked -= amount;
+        /// Emergency unstake with penalty
+        pub fn emergency_unstake(ctx: Context<EmergencyUnstake>, stake_index: u8) -> Result<()> {
+            process_emergency_unstake(ctx, stake_index, None)
+        }
 
-        emit!(UnstakeEvent {
-            user: ctx.accounts.authority.key(),
-            amount,
-            penalty: 0,
-        });
+        /// Emergency unstake part of a locked entry. The penalty and forfeited bonus apply to
+        /// the amount taken out; what stays keeps its lock and must meet the tier minimum.
+        pub fn emergency_unstake_partial(ctx: Context<EmergencyUnstake>, stake_index: u8, amount: u64) -> Result<()> {
+            process_emergency_unstake(ctx, stake_index, Some(amount))
+        }
 
-        msg!("Unstaked {} tokens", amount);
-        Ok(())
-    }
+        /// Leave a lock early without a penalty: the stake stops earning now and its tokens can
+        /// be withdrawn in full with `withdraw_unlocked` once the pool's cooldown has passed.
+        /// The lock bonus held back on the entry is kept and becomes claimable right away.
+        pub fn request_unlock(ctx: Context<RequestUnlock>, stake_index: u8) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
 
-    /// Emergency unstake with penalty
-    pub fn emergency_unstake(ctx: Context<EmergencyUnstake>, stake_index: u8) -> Result<()> {
-        let pool = &mut ctx.accounts.staking_pool;
-        let user_account = &mut ctx.accounts.user_account;
-        let clock = Clock::get()?;
+            // A freshly created queue has no discriminator until the instruction exits
+            let is_new_queue = ctx.accounts.unlock_queue.as_ref().try_borrow_data()?[..8]
+                .iter()
+                .all(|byte| *byte == 0);
+            let mut unlock_queue = if is_new_queue {
+                let mut unlock_queue = ctx.accounts.unlock_queue.load_init()?;
+                unlock_queue.owner = ctx.accounts.authority.key();
+                unlock_queue.bump = ctx.bumps.unlock_queue;
+                unlock_queue
+            } else {
+                ctx.accounts.unlock_queue.load_mut()?
+            };
 
-        require!(!pool.paused, StakingError::PoolPaused);
-        require!((stake_index as usize) < user_account.stakes.len(), StakingError::InvalidStakeIndex);
+            require!(!pool.is_paused(), StakingError::PoolPaused);
+            require!(pool.unlock_cooldown > 0, StakingError::UnlockCooldownDisabled);
+            require!((stake_index as usize) < user_account.stakes().len(), StakingError::InvalidStakeIndex);
 
-        // Security validations
-        SecurityValidator::validate_rate_limiting(user_account, clock.unix_timestamp, OperationType::EmergencyUnstake)?;
-        SecurityValidator::validate_account_consistency(user_account)?;
+            SecurityValidator::validate_account_consistency(&user_account)?;
 
-        let stake = &mut user_account.stakes[stake_index as usize];
-        require!(stake.is_active, StakingError::StakeNotActive);
-        require!(clock.unix_timestamp < stake.lock_end, StakingError::LockExpired);
+            // Rewards stop at the request. Checkpointing first also rolls over auto-renewing entries.
+            update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
 
-        // Update rewards before unstaking
-        update_user_rewards(user_account, pool, clock.unix_timestamp)?;
+            let stake = &user_account.stakes()[stake_index as usize];
+            require!(stake.is_active(), StakingError::StakeNotActive);
+            require!(clock.unix_timestamp < stake.lock_end, StakingError::LockExpired);
+            let amount = stake.amount;
 
-        let staked_amount = stake.amount;
-        
-        // Calculate progressive penalty (33% at start, 0% at end)
-        let total_duration = stake.lock_end - stake.lock_start;
-        let time_remaining = stake.lock_end - clock.unix_timestamp;
-        let penalty_percent = (time_remaining as u64 * 33) / total_duration as u64;
-        
-        // Validate penalty calculation
-        SecurityValidator::validate_penalty_calculation(stake, clock.unix_timestamp, penalty_percent)?;
-        
-        let penalty_amount = (staked_amount * penalty_percent) / 100;
-        let return_amount = staked_amount - penalty_amount;
+            let stake = &mut user_account.stakes_mut()[stake_index as usize];
+            let tier = stake.lock_period()? as usize;
+            let released_bonus = std::mem::take(&mut stake.locked_bonus);
+            stake.is_active = 0;
+            user_account.pending_rewards += released_bonus;
 
-        // Distribute penalty: 40% burn, 40% rewards pool, 20% treasury
-        let burn_amount = (penalty_amount * 40) / 100;
-        let rewards_amount = (penalty_amount * 40) / 100;
-        let treasury_amount = penalty_amount - burn_amount - rewards_amount;
+            let unlock_at = clock.unix_timestamp + pool.unlock_cooldown as i64;
+            unlock_queue.push(UnlockRequest { amount, unlock_at })?;
 
-        let seeds = &[
-            STAKING_POOL_SEED.as_bytes(),
-            &[pool.bump],
-        ];
-        let signer = &[&seeds[..]];
+            // The tokens stay in the vault but no longer count as staked
+            user_account.total_staked -= amount;
+            user_account.track_loyalty(clock.unix_timestamp);
+            pool.remove_staked(amount, clock.unix_timestamp);
+            pool.remove_tier_stake(tier, amount)?;
+            pool.unlocking += amount;
 
-        // Transfer return amount to user
-        let cpi_ctx = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.staking_vault.to_account_info(),
-                to: ctx.accounts.user_token_account.to_account_info(),
-                authority: ctx.accounts.staking_pool.to_account_info(),
-            },
-            signer,
-        );
-        token::transfer(cpi_ctx, return_amount)?;
+            emit!(UnlockRequestedEvent {
+                user: ctx.accounts.authority.key(),
+                stake_index,
+                amount,
+                unlock_at,
+            });
+
+            msg!("Unlock of {} tokens requested, withdrawable at {}", amount, unlock_at);
+            Ok(())
+        }
+
+        /// Withdraw every queued unlock whose cooldown has passed
+        pub fn withdraw_unlocked(ctx: Context<WithdrawUnlocked>) -> Result<()> {
+            let mut unlock_queue = ctx.accounts.unlock_queue.load_mut()?;
+            let clock = Clock::get()?;
+
+            // The pool signs the transfer below, so it cannot stay borrowed across the CPI
+            let pool_bump = {
+                let pool = ctx.accounts.staking_pool.load()?;
+                require!(!pool.is_paused(), StakingError::PoolPaused);
+                pool.bump
+            };
+
+            let amount = unlock_queue.take_matured(clock.unix_timestamp);
+            require!(amount > 0, StakingError::NothingToWithdraw);
+
+            let seeds = &[
+                STAKING_POOL_SEED.as_bytes(),
+                &[pool_bump],
+            ];
+            let signer = &[&seeds[..]];
 
-        // Transfer penalty portions
-        if burn_amount > 0 {
-            // Burn by transferring to burn address (not implemented in this example)
-            msg!("Burn amount: {}", burn_amount);
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.staking_vault.to_account_info(),
+                    mint: ctx.accounts.staking_mint.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
+                    authority: ctx.accounts.staking_pool.to_account_info(),
+                },
+                signer,
+            );
+            token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.staking_mint.decimals)?;
+
+            ctx.accounts.staking_pool.load_mut()?.unlocking -= amount;
+
+            emit!(UnstakeEvent {
+                user: ctx.accounts.authority.key(),
+                amount,
+                penalty: 0,
+            });
+
+            msg!("Withdrew {} unlocked tokens, {} requests still cooling down", amount, unlock_queue.count);
+            Ok(())
+        }
+
+        /// Claim accumulated $WePee rewards
+        pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
+
+            require!(!ctx.accounts.staking_pool.load()?.is_paused(), StakingError::PoolPaused);
+
+            let reward_amount = claim_pending_rewards(
+                &ctx.accounts.staking_pool,
+                &mut user_account,
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.user_reward_account,
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
+
+            emit!(ClaimRewardsEvent {
+                user: ctx.accounts.authority.key(),
+                amount: reward_amount,
+            });
+
+            msg!("Claimed {} $WePee rewards", reward_amount);
+            Ok(())
         }
 
-        if rewards_amount > 0 {
-            // Add to reward vault
-            msg!("Added {} to rewards pool", rewards_amount);
+        /// Claim accumulated $WePee rewards into any reward token account
+        pub fn claim_rewards_to(ctx: Context<ClaimRewardsTo>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
+
+            require!(!ctx.accounts.staking_pool.load()?.is_paused(), StakingError::PoolPaused);
+
+            let reward_amount = claim_pending_rewards(
+                &ctx.accounts.staking_pool,
+                &mut user_account,
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.destination,
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
+
+            emit!(ClaimRewardsEvent {
+                user: ctx.accounts.authority.key(),
+                amount: reward_amount,
+            });
+
+            msg!("Claimed {} $WePee rewards to {}", reward_amount, ctx.accounts.destination.key());
+            Ok(())
+        }
+
+        /// Claim a user's rewards into their reward ATA as their delegate with DELEGATE_CLAIM
+        pub fn claim_rewards_for(ctx: Context<ClaimRewardsFor>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
+
+            require!(!ctx.accounts.staking_pool.load()?.is_paused(), StakingError::PoolPaused);
+
+            let reward_amount = claim_pending_rewards(
+                &ctx.accounts.staking_pool,
+                &mut user_account,
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.owner_reward_account,
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
+
+            emit!(ClaimRewardsEvent {
+                user: user_account.authority,
+                amount: reward_amount,
+            });
+
+            msg!("Claimed {} $WePee rewards on behalf of {}", reward_amount, user_account.authority);
+            Ok(())
+        }
+
+        /// Stake tokens without a lock, minting liquid receipt tokens at the current exchange rate.
+        /// Only pools paying rewards in the staking token have a receipt mint.
+        pub fn stake_flexible(ctx: Context<StakeFlexible>, amount: u64) -> Result<()> {
+            let clock = Clock::get()?;
+
+            {
+                let pool = ctx.accounts.staking_pool.load()?;
+                require!(!pool.is_paused(), StakingError::PoolPaused);
+                require!(amount > 0, StakingError::InvalidAmount);
+                require!(pool.receipt_mint != Pubkey::default(), StakingError::FlexibleStakingDisabled);
+                require!(!pool.is_allowlisted(), StakingError::AllowlistProofRequired);
+                SecurityValidator::validate_mint_extensions(
+                    &ctx.accounts.staking_mint.to_account_info(),
+                    pool.extension_allowlist(),
+                )?;
+            }
+
+            // Accrue base rate rewards so new stakers enter at the up-to-date rate
+            sync_flexible_rewards(
+                &ctx.accounts.staking_pool,
+                &ctx.accounts.reward_vault,
+                &mut ctx.accounts.staking_vault,
+                &ctx.accounts.staking_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
+
+            // Launch caps. Receipts the staker already holds count toward the per-user cap
+            // at what they currently redeem for.
+            {
+                let pool = ctx.accounts.staking_pool.load()?;
+                let user_staked = receipt_backed_amount(
+                    ctx.accounts.user_receipt_account.amount,
+                    pool.flexible_staked,
+                    ctx.accounts.receipt_mint.supply,
+                )?;
+                validate_stake_caps(&pool, user_staked, None, amount)?;
+            }
+
+            // Transfer tokens to vault, crediting only what arrives after any transfer fee
+            let received = deposit_to_vault(
+                &ctx.accounts.user_token_account,
+                &mut ctx.accounts.staking_vault,
+                &ctx.accounts.staking_mint,
+                ctx.accounts.authority.to_account_info(),
+                &ctx.accounts.token_program,
+                &[],
+                amount,
+            )?;
+
+            let (flexible_staked, pool_bump) = {
+                let pool = ctx.accounts.staking_pool.load()?;
+                (pool.flexible_staked, pool.bump)
+            };
+            let receipt_amount = calculate_receipt_amount(
+                received,
+                flexible_staked,
+                ctx.accounts.receipt_mint.supply,
+            )?;
+            require!(receipt_amount > 0, StakingError::InvalidAmount);
+
+            // Mint receipt tokens to user
+            let seeds = &[
+                STAKING_POOL_SEED.as_bytes(),
+                &[pool_bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.receipt_mint.to_account_info(),
+                    to: ctx.accounts.user_receipt_account.to_account_info(),
+                    authority: ctx.accounts.staking_pool.to_account_info(),
+                },
+                signer,
+            );
+            token_interface::mint_to(cpi_ctx, receipt_amount)?;
+
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            pool.flexible_staked += received;
+            pool.add_staked(received, clock.unix_timestamp);
+
+            emit!(FlexibleStakeEvent {
+                user: ctx.accounts.authority.key(),
+                amount: received,
+                receipt_amount,
+            });
+
+            msg!("Flexible staked {} tokens for {} receipt tokens", received, receipt_amount);
+            Ok(())
         }
 
-        if treasury_amount > 0 {
-            // Transfer to treasury
+        /// Burn liquid receipt tokens to redeem the underlying tokens at the current exchange rate
+        pub fn unstake_flexible(ctx: Context<UnstakeFlexible>, receipt_amount: u64) -> Result<()> {
+            let clock = Clock::get()?;
+
+            require!(!ctx.accounts.staking_pool.load()?.is_paused(), StakingError::PoolPaused);
+            require!(receipt_amount > 0, StakingError::InvalidAmount);
+
+            sync_flexible_rewards(
+                &ctx.accounts.staking_pool,
+                &ctx.accounts.reward_vault,
+                &mut ctx.accounts.staking_vault,
+                &ctx.accounts.staking_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
+
+            let (flexible_staked, pool_bump) = {
+                let pool = ctx.accounts.staking_pool.load()?;
+                (pool.flexible_staked, pool.bump)
+            };
+            let amount = calculate_redeem_amount(
+                receipt_amount,
+                flexible_staked,
+                ctx.accounts.receipt_mint.supply,
+            )?;
+            require!(amount > 0, StakingError::InvalidAmount);
+
+            // Burn receipt tokens from user
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Burn {
+                    mint: ctx.accounts.receipt_mint.to_account_info(),
+                    from: ctx.accounts.user_receipt_account.to_account_info(),
+                    authority: ctx.accounts.authority.to_account_info(),
+                },
+            );
+            token_interface::burn(cpi_ctx, receipt_amount)?;
+
+            // Transfer tokens back to user
+            let seeds = &[
+                STAKING_POOL_SEED.as_bytes(),
+                &[pool_bump],
+            ];
+            let signer = &[&seeds[..]];
+
             let cpi_ctx = CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                Transfer {
+                TransferChecked {
                     from: ctx.accounts.staking_vault.to_account_info(),
-                    to: ctx.accounts.treasury_account.to_account_info(),
+                    mint: ctx.accounts.staking_mint.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
                     authority: ctx.accounts.staking_pool.to_account_info(),
                 },
                 signer,
             );
-            token::transfer(cpi_ctx, treasury_amount)?;
+            token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.staking_mint.decimals)?;
+
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            pool.flexible_staked -= amount;
+            pool.remove_staked(amount, clock.unix_timestamp);
+
+            emit!(FlexibleUnstakeEvent {
+                user: ctx.accounts.authority.key(),
+                amount,
+                receipt_amount,
+            });
+
+            msg!("Flexible unstaked {} tokens for {} receipt tokens", amount, receipt_amount);
+            Ok(())
         }
 
-        // Mark stake as inactive
-        stake.is_active = false;
+        /// Compound pending rewards into a locked stake entry as added principal
+        pub fn compound(ctx: Context<Compound>, stake_index: u8) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
 
-        // Update totals
-        user_account.total_staked -= staked_amount;
-        pool.total_staked -= staked_amount;
+            require!(!ctx.accounts.staking_pool.load()?.is_paused(), StakingError::PoolPaused);
 
-        emit!(UnstakeEvent {
-            user: ctx.accounts.authority.key(),
-            amount: return_amount,
-            penalty: penalty_amount,
-        });
+            let amount = compound_into_entry(
+                &ctx.accounts.staking_pool,
+                &mut user_account,
+                stake_index as usize,
+                &ctx.accounts.reward_vault,
+                &mut ctx.accounts.staking_vault,
+                &ctx.accounts.staking_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
 
-        msg!("Emergency unstaked {} tokens with {}% penalty ({})", 
-            return_amount, penalty_percent, penalty_amount);
-        Ok(())
-    }
+            emit!(CompoundEvent {
+                user: user_account.authority,
+                stake_index,
+                amount,
+            });
 
-    /// Claim accumulated $WePee rewards
-    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
-        let pool = &mut ctx.accounts.staking_pool;
-        let user_account = &mut ctx.accounts.user_account;
-        let clock = Clock::get()?;
+            msg!("Compounded {} rewards into stake {}", amount, stake_index);
+            Ok(())
+        }
 
-        require!(!pool.paused, StakingError::PoolPaused);
+        /// Set (or clear with the default pubkey) an operator allowed to manage positions
+        /// without custody. Unstaking stays owner-only.
+        pub fn set_delegate(ctx: Context<SetDelegate>, delegate: Pubkey, permissions: u8) -> Result<()> {
+            require!(permissions & !DELEGATE_ALL == 0, StakingError::InvalidDelegatePermissions);
 
-        // Update rewards
-        update_user_rewards(user_account, pool, clock.unix_timestamp)?;
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            user_account.delegate = delegate;
+            user_account.delegate_permissions = if delegate == Pubkey::default() { 0 } else { permissions };
 
-        let reward_amount = user_account.pending_rewards;
-        require!(reward_amount > 0, StakingError::NoRewardsToClaim);
+            emit!(DelegateSetEvent {
+                user: user_account.authority,
+                delegate,
+                permissions: user_account.delegate_permissions,
+            });
 
-        let seeds = &[
-            STAKING_POOL_SEED.as_bytes(),
-            &[pool.bump],
-        ];
-        let signer = &[&seeds[..]];
+            msg!("Delegate set to {} with permissions {:#06b}", delegate, user_account.delegate_permissions);
+            Ok(())
+        }
 
-        // Transfer $WePee rewards to user
-        let cpi_ctx = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.reward_vault.to_account_info(),
-                to: ctx.accounts.user_reward_account.to_account_info(),
-                authority: ctx.accounts.staking_pool.to_account_info(),
-            },
-            signer,
-        );
-        token::transfer(cpi_ctx, reward_amount)?;
+        /// Move a locked entry to an equal or longer lock tier, restarting the lock from now
+        pub fn extend_lock(ctx: Context<ExtendLock>, stake_index: u8, lock_period: LockPeriod) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
 
-        user_account.pending_rewards = 0;
+            require!(!pool.is_paused(), StakingError::PoolPaused);
+            require!((stake_index as usize) < user_account.stakes().len(), StakingError::InvalidStakeIndex);
 
-        emit!(ClaimRewardsEvent {
-            user: ctx.accounts.authority.key(),
-            amount: reward_amount,
-        });
+            SecurityValidator::validate_account_consistency(&user_account)?;
 
-        msg!("Claimed {} $WePee rewards", reward_amount);
-        Ok(())
-    }
+            // Update rewards before changing the multiplier
+            update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
 
-    /// Admin function to pause/unpause the pool
-    pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
-        let pool = &mut ctx.accounts.staking_pool;
-        require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
-        
-        pool.paused = paused;
-        msg!("Pool paused status set to: {}", paused);
-        Ok(())
-    }
-}
+            let owner = user_account.authority;
+            let stake = &mut user_account.stakes_mut()[stake_index as usize];
+            require!(stake.is_active(), StakingError::StakeNotActive);
+            require!(clock.unix_timestamp < stake.lock_end, StakingError::LockExpired);
+            require!(
+                get_lock_duration(lock_period) >= get_lock_duration(stake.lock_period()?)
+                    && clock.unix_timestamp + get_lock_duration(lock_period) >= stake.lock_end,
+                StakingError::InvalidLockExtension
+            );
+            require!(stake.amount >= get_min_stake(&pool, lock_period), StakingError::BelowMinimumStake);
 
-// Helper functions
-fn get_lock_multiplier(lock_period: LockPeriod) -> u64 {
-    match lock_period {
-        LockPeriod::OneDay => 1000,     // 1.0x (scaled by 1000)
-        LockPeriod::OneWeek => 1250,    // 1.25x
-        LockPeriod::ThreeMonths => 2000, // 2.0x
-        LockPeriod::SixMonths => 3000,   // 3.0x
-    }
-}
+            move_tier_stake(&mut pool, stake.lock_period()?, lock_period, stake.amount)?;
+            relock_entry(stake, &pool, lock_period, clock.unix_timestamp);
 
-fn update_user_rewards(
-    user_account: &mut UserAccount,
-    pool: &StakingPool,
-    current_time: i64,
-) -> Result<()> {
-    let time_diff = current_time - user_account.last_reward_time;
-    if time_diff <= 0 {
-        return Ok(());
-    }
+            emit!(RelockEvent {
+                user: owner,
+                stake_index,
+                lock_period,
+                lock_end: stake.lock_end,
+                multiplier: stake.multiplier,
+            });
 
-    let mut total_rewards = 0u64;
+            msg!("Extended stake {} to {:?} lock period", stake_index, lock_period);
+            Ok(())
+        }
 
-    // Calculate rewards for each active stake
-    for stake in &user_account.stakes {
-        if !stake.is_active {
-            continue;
+        /// Relock an expired entry into a new lock period without withdrawing it
+        pub fn restake(ctx: Context<Restake>, stake_index: u8, lock_period: LockPeriod) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
+
+            require!(!pool.is_paused(), StakingError::PoolPaused);
+            require!((stake_index as usize) < user_account.stakes().len(), StakingError::InvalidStakeIndex);
+
+            SecurityValidator::validate_account_consistency(&user_account)?;
+
+            // Update rewards so accrual up to the old lock end is kept
+            update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
+
+            let owner = user_account.authority;
+            let stake = &mut user_account.stakes_mut()[stake_index as usize];
+            require!(stake.is_active(), StakingError::StakeNotActive);
+            require!(clock.unix_timestamp >= stake.lock_end, StakingError::StillLocked);
+            require!(stake.amount >= get_min_stake(&pool, lock_period), StakingError::BelowMinimumStake);
+
+            move_tier_stake(&mut pool, stake.lock_period()?, lock_period, stake.amount)?;
+            relock_entry(stake, &pool, lock_period, clock.unix_timestamp);
+
+            emit!(RelockEvent {
+                user: owner,
+                stake_index,
+                lock_period,
+                lock_end: stake.lock_end,
+                multiplier: stake.multiplier,
+            });
+
+            msg!("Restaked stake {} with {:?} lock period", stake_index, lock_period);
+            Ok(())
         }
 
-        // Calculate effective staking time (only while locked or after unlock)
-        let stake_start = std::cmp::max(stake.lock_start, user_account.last_reward_time);
-        let stake_end = std::cmp::min(current_time, stake.lock_end);
-        
-        if stake_end > stake_start {
-            let stake_duration = stake_end - stake_start;
-            
-            // Base reward calculation: (amount / 1000) * rate * days * multiplier
-            let days = stake_duration as u64 / 86400; // Convert seconds to days
-            let base_reward = (stake.amount * pool.reward_rate * days) / (1000 * 1_000_000); // Scale down
-            let multiplied_reward = (base_reward * stake.multiplier) / 1000; // Apply multiplier
-            
-            total_rewards += multiplied_reward;
+        /// Opt in or out of permissionless auto-compounding
+        pub fn set_auto_compound(ctx: Context<SetAutoCompound>, enabled: bool) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            user_account.auto_compound = enabled as u8;
+
+            msg!("Auto-compound set to: {}", enabled);
+            Ok(())
         }
-    }
 
-    user_account.pending_rewards += total_rewards;
-    user_account.last_reward_time = current_time;
+        /// Opt a stake in or out of renewing its lock at expiry. An entry that has already
+        /// expired is relocked in its tier from now.
+        pub fn set_auto_renew(ctx: Context<SetAutoRenew>, stake_index: u8, enabled: bool) -> Result<()> {
+            let pool = ctx.accounts.staking_pool.load()?;
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
 
-    Ok(())
-}
+            require!((stake_index as usize) < user_account.stakes().len(), StakingError::InvalidStakeIndex);
+            require!(user_account.stakes()[stake_index as usize].is_active(), StakingError::StakeNotActive);
 
-// Constants
-const STAKING_POOL_SEED: &str = "staking_pool";
-const USER_ACCOUNT_SEED: &str = "user_account";
+            // Settle rollovers due under the old setting
+            update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
 
-// Minimum stake amounts (in token base units)
-const MIN_STAKE_24H: u64 = 100_000_000;    // 100 tokens
-const MIN_STAKE_1W: u64 = 250_000_000;     // 250 tokens
-const MIN_STAKE_3M: u64 = 500_000_000;     // 500 tokens
-const MIN_STAKE_6M: u64 = 1_000_000_000;   // 1000 tokens
+            let bit = 1u16 << stake_index;
+            if enabled {
+                user_account.auto_renew |= bit;
+                let stake = &mut user_account.stakes_mut()[stake_index as usize];
+                if clock.unix_timestamp >= stake.lock_end {
+                    relock_entry(stake, &pool, stake.lock_period()?, clock.unix_timestamp);
+                }
+            } else {
+                user_account.auto_renew &= !bit;
+            }
 
-// Account structures
-#[account]
-pub struct StakingPool {
-    pub authority: Pubkey,
-    pub staking_mint: Pubkey,
-    pub reward_mint: Pubkey,
-    pub staking_vault: Pubkey,
-    pub reward_vault: Pubkey,
-    pub total_staked: u64,
-    pub reward_rate: u64, // Base $WePee per 1000 staked per day (scaled by 1e6)
-    pub bump: u8,
-    pub paused: bool,
-}
+            msg!("Auto-renew for stake {} set to: {}", stake_index, enabled);
+            Ok(())
+        }
 
-#[account]
-pub struct UserAccount {
-    pub authority: Pubkey,
-    pub total_staked: u64,
-    pub pending_rewards: u64,
-    pub last_reward_time: i64,
-    pub stakes: Vec<StakeEntry>,
-    pub bump: u8,
-}
+        /// Permissionless crank that compounds rewards for a user who opted in.
+        /// Rewards go into the user's longest-running locked entry.
+        pub fn auto_compound(ctx: Context<AutoCompound>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
-pub struct StakeEntry {
-    pub amount: u64,
-    pub lock_period: LockPeriod,
-    pub lock_start: i64,
-    pub lock_end: i64,
-    pub multiplier: u64, // Scaled by 1000 (1000 = 1.0x)
-    pub is_active: bool,
-}
+            {
+                let pool = ctx.accounts.staking_pool.load()?;
+                require!(!pool.is_paused(), StakingError::PoolPaused);
+                require!(pool.reward_mint == pool.staking_mint, StakingError::CompoundMintMismatch);
+            }
+            require!(user_account.is_auto_compound(), StakingError::AutoCompoundDisabled);
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
-pub enum LockPeriod {
-    OneDay,
-    OneWeek,
-    ThreeMonths,
-    SixMonths,
-}
+            let stake_index = select_auto_compound_entry(&user_account, clock.unix_timestamp)
+                .ok_or(StakingError::NoCompoundableStake)?;
 
-// Context structures
-#[derive(Accounts)]
-#[instruction(bump: u8)]
-pub struct InitializePool<'info> {
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + 32 + 32 + 32 + 32 + 32 + 8 + 8 + 1 + 1,
-        seeds = [STAKING_POOL_SEED.as_bytes()],
-        bump = bump
-    )]
-    pub staking_pool: Account<'info, StakingPool>,
-    
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    
-    pub staking_mint: Account<'info, token::Mint>,
-    pub reward_mint: Account<'info, token::Mint>,
-    
-    #[account(
-        init,
-        payer = authority,
-        token::mint = staking_mint,
-        token::authority = staking_pool,
-    )]
-    pub staking_vault: Account<'info, TokenAccount>,
-    
-    #[account(
-        init,
-        payer = authority,
-        token::mint = reward_mint,
-        token::authority = staking_pool,
-    )]
-    pub reward_vault: Account<'info, TokenAccount>,
-    
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+            let amount = compound_into_entry(
+                &ctx.accounts.staking_pool,
+                &mut user_account,
+                stake_index,
+                &ctx.accounts.reward_vault,
+                &mut ctx.accounts.staking_vault,
+                &ctx.accounts.staking_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
 
-#[derive(Accounts)]
-#[instruction(bump: u8)]
-pub struct InitializeUser<'info> {
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + 32 + 8 + 8 + 8 + (4 + 10 * (8 + 1 + 8 + 8 + 8 + 1)) + 1, // Vec<StakeEntry> with max 10 entries
-        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
-        bump = bump
-    )]
-    pub user_account: Account<'info, UserAccount>,
-    
-    #[account(mut)]
-    pub authority: Signer<'info>,
+            emit!(CompoundEvent {
+                user: user_account.authority,
+                stake_index: stake_index as u8,
+                amount,
+            });
+
+            msg!("Auto-compounded {} rewards into stake {}", amount, stake_index);
+            Ok(())
+        }
+
+        /// Permissionless keeper crank: checkpoints rewards and runs due auto-compounds for a
+        /// batch of user accounts passed as remaining accounts. The bounty is paid per entry
+        /// compounded or rolled over, capped per call and drawn from the pool's bounty budget.
+        pub fn crank<'info>(ctx: Context<'_, '_, 'info, 'info, Crank<'info>>) -> Result<()> {
+            let clock = Clock::get()?;
+
+            require!(!ctx.accounts.staking_pool.load()?.is_paused(), StakingError::PoolPaused);
+            require!(
+                !ctx.remaining_accounts.is_empty() && ctx.remaining_accounts.len() <= MAX_CRANK_BATCH,
+                StakingError::InvalidCrankBatch
+            );
+
+            // Pool checkpoint
+            sync_flexible_rewards(
+                &ctx.accounts.staking_pool,
+                &ctx.accounts.reward_vault,
+                &mut ctx.accounts.staking_vault,
+                &ctx.accounts.staking_mint,
+                &ctx.accounts.token_program,
+                clock.unix_timestamp,
+            )?;
+
+            let mut users_serviced = 0u64;
+            let mut entries_serviced = 0u64;
+            let mut total_compounded = 0u64;
+
+            for account_info in ctx.remaining_accounts.iter() {
+                require!(account_info.is_writable, StakingError::InvalidCrankAccount);
+                let user_loader = AccountLoader::<UserAccount>::try_from(account_info)?;
+                let mut user_account = user_loader.load_mut()?;
+
+                let expected_address = Pubkey::create_program_address(
+                    &[
+                        USER_ACCOUNT_SEED.as_bytes(),
+                        user_account.authority.as_ref(),
+                        &[user_account.bump],
+                    ],
+                    ctx.program_id,
+                ).map_err(|_| error!(StakingError::InvalidCrankAccount))?;
+                require!(expected_address == account_info.key(), StakingError::InvalidCrankAccount);
+
+                let compound_index = {
+                    let pool = ctx.accounts.staking_pool.load()?;
+
+                    // Rate limit: each user can only be serviced once per crank interval
+                    if clock.unix_timestamp - user_account.last_crank_time < pool.crank_interval {
+                        continue;
+                    }
+
+                    entries_serviced += user_account.due_rollovers(clock.unix_timestamp);
+                    update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
+
+                    let compoundable = user_account.is_auto_compound()
+                        && pool.reward_mint == pool.staking_mint
+                        && user_account.pending_rewards >= MIN_COMPOUND_TOKENS * token_unit(&pool);
+
+                    // Users whose compound would break a stake cap are skipped, not failed
+                    select_auto_compound_entry(&user_account, clock.unix_timestamp)
+                        .filter(|&index| compoundable && validate_compound_caps(&pool, &user_account, index).is_ok())
+                };
+
+                if let Some(stake_index) = compound_index {
+                    let amount = compound_into_entry(
+                        &ctx.accounts.staking_pool,
+                        &mut user_account,
+                        stake_index,
+                        &ctx.accounts.reward_vault,
+                        &mut ctx.accounts.staking_vault,
+                        &ctx.accounts.staking_mint,
+                        &ctx.accounts.token_program,
+                        clock.unix_timestamp,
+                    )?;
+                    total_compounded += amount;
+                    entries_serviced += 1;
+
+                    emit!(CompoundEvent {
+                        user: user_account.authority,
+                        stake_index: stake_index as u8,
+                        amount,
+                    });
+                }
+
+                user_account.last_crank_time = clock.unix_timestamp;
+                users_serviced += 1;
+            }
+
+            // Pay the keeper bounty from the reward vault, out of the remaining budget
+            ctx.accounts.reward_vault.reload()?;
+            let (bounty, pool_bump) = {
+                let mut pool = ctx.accounts.staking_pool.load_mut()?;
+                let bounty = pool.crank_bounty
+                    .saturating_mul(entries_serviced)
+                    .min(pool.crank_bounty_cap)
+                    .min(pool.crank_bounty_budget)
+                    .min(ctx.accounts.reward_vault.amount);
+                pool.crank_bounty_budget -= bounty;
+                (bounty, pool.bump)
+            };
+
+            if bounty > 0 {
+                let seeds = &[
+                    STAKING_POOL_SEED.as_bytes(),
+                    &[pool_bump],
+                ];
+                let signer = &[&seeds[..]];
+
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.reward_vault.to_account_info(),
+                        mint: ctx.accounts.reward_mint.to_account_info(),
+                        to: ctx.accounts.cranker_reward_account.to_account_info(),
+                        authority: ctx.accounts.staking_pool.to_account_info(),
+                    },
+                    signer,
+                );
+                token_interface::transfer_checked(cpi_ctx, bounty, ctx.accounts.reward_mint.decimals)?;
+            }
+
+            emit!(CrankEvent {
+                cranker: ctx.accounts.cranker.key(),
+                users_serviced,
+                entries_serviced,
+                total_compounded,
+                bounty,
+            });
+
+            msg!(
+                "Crank serviced {} users and {} entries, compounded {}, bounty {}",
+                users_serviced,
+                entries_serviced,
+                total_compounded,
+                bounty
+            );
+            Ok(())
+        }
+
+        /// Admin function to configure the keeper crank bounty and per-user rate limit.
+        /// `crank_bounty` is paid per entry serviced, at most `bounty_cap` per call, until
+        /// `bounty_budget` (replacing the remaining budget) runs out.
+        pub fn set_crank_config(
+            ctx: Context<SetCrankConfig>,
+            crank_bounty: u64,
+            crank_interval: i64,
+            bounty_cap: u64,
+            bounty_budget: u64,
+        ) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(crank_interval >= MIN_CRANK_INTERVAL, StakingError::InvalidCrankConfig);
+            require!(crank_bounty <= bounty_cap, StakingError::InvalidCrankConfig);
+
+            pool.crank_bounty = crank_bounty;
+            pool.crank_interval = crank_interval;
+            pool.crank_bounty_cap = bounty_cap;
+            pool.crank_bounty_budget = bounty_budget;
+
+            msg!(
+                "Crank bounty set to {} (cap {}, budget {}) with interval {}s",
+                crank_bounty,
+                bounty_cap,
+                bounty_budget,
+                crank_interval
+            );
+            Ok(())
+        }
+
+        /// Close a user account with no active stakes, paying out any pending rewards
+        /// and returning the rent to the owner
+        pub fn close_user_account(ctx: Context<CloseUserAccount>) -> Result<()> {
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            let clock = Clock::get()?;
+
+            require!(
+                user_account.stakes().iter().all(|stake| !stake.is_active()),
+                StakingError::HasActiveStakes
+            );
+
+            {
+                let pool = ctx.accounts.staking_pool.load()?;
+                update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
+            }
+
+            if user_account.pending_rewards > 0 {
+                require!(!ctx.accounts.staking_pool.load()?.is_paused(), StakingError::PoolPaused);
+
+                let reward_amount = claim_pending_rewards(
+                    &ctx.accounts.staking_pool,
+                    &mut user_account,
+                    &ctx.accounts.reward_vault,
+                    &ctx.accounts.user_reward_account,
+                    &ctx.accounts.reward_mint,
+                    &ctx.accounts.token_program,
+                    clock.unix_timestamp,
+                )?;
+
+                emit!(ClaimRewardsEvent {
+                    user: ctx.accounts.authority.key(),
+                    amount: reward_amount,
+                });
+            }
+
+            msg!("User staking account closed for: {}", ctx.accounts.authority.key());
+            Ok(())
+        }
+
+        /// Admin function to close an empty pool and its vaults, returning the rent.
+        /// Requires no staked tokens, no outstanding receipt tokens and no pending unlocks.
+        /// Whatever the vaults still hold (rounding dust, donations, unwithdrawn penalty
+        /// rewards, undistributed rewards) belongs to no staker and is swept to the
+        /// destination accounts first.
+        pub fn close_pool(ctx: Context<ClosePool>) -> Result<()> {
+            let pool_bump = {
+                let pool = ctx.accounts.staking_pool.load()?;
+                require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+                require!(
+                    pool.total_staked == 0 && pool.flexible_staked == 0 && pool.unlocking == 0,
+                    StakingError::PoolNotEmpty
+                );
+                pool.bump
+            };
+
+            let seeds = &[
+                STAKING_POOL_SEED.as_bytes(),
+                &[pool_bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            let staking_swept = ctx.accounts.staking_vault.amount;
+            if staking_swept > 0 {
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.staking_vault.to_account_info(),
+                        mint: ctx.accounts.staking_mint.to_account_info(),
+                        to: ctx.accounts.staking_destination.to_account_info(),
+                        authority: ctx.accounts.staking_pool.to_account_info(),
+                    },
+                    signer,
+                );
+                token_interface::transfer_checked(cpi_ctx, staking_swept, ctx.accounts.staking_mint.decimals)?;
+            }
+
+            let reward_swept = ctx.accounts.reward_vault.amount;
+            if reward_swept > 0 {
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.reward_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.reward_vault.to_account_info(),
+                        mint: ctx.accounts.reward_mint.to_account_info(),
+                        to: ctx.accounts.reward_destination.to_account_info(),
+                        authority: ctx.accounts.staking_pool.to_account_info(),
+                    },
+                    signer,
+                );
+                token_interface::transfer_checked(cpi_ctx, reward_swept, ctx.accounts.reward_mint.decimals)?;
+            }
+
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                CloseAccount {
+                    account: ctx.accounts.staking_vault.to_account_info(),
+                    destination: ctx.accounts.authority.to_account_info(),
+                    authority: ctx.accounts.staking_pool.to_account_info(),
+                },
+                signer,
+            );
+            token_interface::close_account(cpi_ctx)?;
+
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.reward_token_program.to_account_info(),
+                CloseAccount {
+                    account: ctx.accounts.reward_vault.to_account_info(),
+                    destination: ctx.accounts.authority.to_account_info(),
+                    authority: ctx.accounts.staking_pool.to_account_info(),
+                },
+                signer,
+            );
+            token_interface::close_account(cpi_ctx)?;
+
+            msg!("Staking pool closed, swept {} staking and {} reward tokens", staking_swept, reward_swept);
+            Ok(())
+        }
+
+        /// Admin function to upgrade the pool account to the current layout in place
+        pub fn migrate_pool(ctx: Context<MigratePool>) -> Result<()> {
+            let pool_info = ctx.accounts.staking_pool.to_account_info();
+            migration::validate_discriminator(&pool_info, ctx.program_id, StakingPool::DISCRIMINATOR)?;
+
+            let data_len = pool_info.data_len();
+            require!(data_len < STAKING_POOL_SPACE, StakingError::AlreadyMigrated);
+            require!(data_len == STAKING_POOL_V0_SPACE, StakingError::InvalidAccountLayout);
+
+            let pool_v0 = StakingPoolV0::deserialize(&mut &pool_info.try_borrow_data()?[8..])?;
+            let decimals = ctx.accounts.staking_mint.decimals;
+            let pool = pool_v0.upgrade(decimals, scale_min_stakes(decimals)?);
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(ctx.accounts.staking_mint.key() == pool.staking_mint, StakingError::InvalidAccountLayout);
+
+            migration::resize_account(
+                &pool_info,
+                &ctx.accounts.authority.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                STAKING_POOL_SPACE,
+            )?;
+            migration::write_zero_copy(&pool_info, &pool)?;
+
+            msg!("Staking pool migrated to version {}", STAKING_POOL_VERSION);
+            Ok(())
+        }
+
+        /// Upgrade a user account to the current layout in place, adding its positions to the
+        /// pool's per-tier totals. Anyone may pay for the migration.
+        pub fn migrate_user(ctx: Context<MigrateUser>) -> Result<()> {
+            let user_info = ctx.accounts.user_account.to_account_info();
+            migration::validate_discriminator(&user_info, ctx.program_id, UserAccount::DISCRIMINATOR)?;
+
+            let data_len = user_info.data_len();
+            require!(data_len < USER_ACCOUNT_SPACE, StakingError::AlreadyMigrated);
+            require!(data_len == USER_ACCOUNT_V0_SPACE, StakingError::InvalidAccountLayout);
+
+            let user_account = UserAccountV0::deserialize(&mut &user_info.try_borrow_data()?[8..])?.upgrade()?;
+
+            let expected_address = Pubkey::create_program_address(
+                &[
+                    USER_ACCOUNT_SEED.as_bytes(),
+                    user_account.authority.as_ref(),
+                    &[user_account.bump],
+                ],
+                ctx.program_id,
+            ).map_err(|_| error!(StakingError::InvalidAccountLayout))?;
+            require!(expected_address == user_info.key(), StakingError::InvalidAccountLayout);
+
+            migration::resize_account(
+                &user_info,
+                &ctx.accounts.payer.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                USER_ACCOUNT_SPACE,
+            )?;
+            migration::write_zero_copy(&user_info, &user_account)?;
+
+            // Backfill per-tier totals, which original pools did not track
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            let mut backfilled = 0u64;
+            for stake in user_account.stakes().iter().filter(|stake| stake.is_active()) {
+                pool.tier_staked[stake.lock_period()? as usize] += stake.amount;
+                backfilled += stake.amount;
+            }
+            pool.unmigrated_staked = pool.unmigrated_staked
+                .checked_sub(backfilled)
+                .ok_or(StakingError::TierAccountingMismatch)?;
+
+            msg!("User account {} migrated to version {}", user_info.key(), USER_ACCOUNT_VERSION);
+            Ok(())
+        }
+
+        /// Admin function to configure the loyalty bonus: `rate_bps_per_day` of extra rewards per
+        /// day of uninterrupted stake, up to `cap_bps`. An emergency unstake takes away
+        /// `emergency_decay_bps` of the accumulated time (10_000 = reset). Applies to accrual
+        /// not yet checkpointed.
+        pub fn set_loyalty_config(
+            ctx: Context<SetLoyaltyConfig>,
+            rate_bps_per_day: u32,
+            cap_bps: u32,
+            emergency_decay_bps: u32,
+        ) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(
+                cap_bps <= MAX_LOYALTY_BPS && emergency_decay_bps <= BPS_DENOMINATOR,
+                StakingError::InvalidLoyaltyConfig
+            );
+
+            pool.loyalty_rate_bps = rate_bps_per_day;
+            pool.loyalty_cap_bps = cap_bps;
+            pool.loyalty_decay_bps = emergency_decay_bps;
+
+            msg!(
+                "Loyalty set: {} bps per day up to {} bps, {} bps decay on emergency unstake",
+                rate_bps_per_day, cap_bps, emergency_decay_bps
+            );
+            Ok(())
+        }
+
+        /// Admin function to set the emergency unstake penalty for a lock tier: `max_bps` with the
+        /// whole lock remaining, falling along `curve` to `floor_bps`, with `burn_bps` of the
+        /// penalty burned and `rewards_bps` held for rewards. Applies to stakes locked from now on.
+        pub fn set_penalty_config(
+            ctx: Context<SetPenaltyConfig>,
+            lock_period: LockPeriod,
+            max_bps: u16,
+            floor_bps: u16,
+            curve: PenaltyCurve,
+            burn_bps: u16,
+            rewards_bps: u16,
+        ) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(
+                max_bps <= MAX_PENALTY_BPS
+                    && floor_bps <= max_bps
+                    && burn_bps as u32 + rewards_bps as u32 <= BPS_DENOMINATOR,
+                StakingError::InvalidPenaltyConfig
+            );
+
+            pool.penalty_configs[lock_period as usize] = PenaltyConfig {
+                max_bps,
+                floor_bps,
+                burn_bps,
+                rewards_bps,
+                curve: curve as u8,
+                padding: [0; 1],
+            };
+
+            msg!(
+                "Penalty for {:?} set: {:?} from {} to {} bps, {} bps burned, {} bps to rewards",
+                lock_period, curve, max_bps, floor_bps, burn_bps, rewards_bps
+            );
+            Ok(())
+        }
+
+        /// Admin function to move `amount` of the penalty rewards share out of the staking
+        /// vault, e.g. into the reward vault when rewards are paid in the staking token
+        pub fn withdraw_penalty_rewards(ctx: Context<WithdrawPenaltyRewards>, amount: u64) -> Result<()> {
+            // The pool signs the transfer below, so it cannot stay borrowed across the CPI
+            let pool_bump = {
+                let pool = ctx.accounts.staking_pool.load()?;
+                require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+                require!(amount > 0 && amount <= pool.penalty_rewards, StakingError::InvalidAmount);
+                pool.bump
+            };
+
+            let seeds = &[
+                STAKING_POOL_SEED.as_bytes(),
+                &[pool_bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.staking_vault.to_account_info(),
+                    mint: ctx.accounts.staking_mint.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: ctx.accounts.staking_pool.to_account_info(),
+                },
+                signer,
+            );
+            token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.staking_mint.decimals)?;
+
+            ctx.accounts.staking_pool.load_mut()?.penalty_rewards -= amount;
+
+            msg!("Withdrew {} penalty reward tokens to {}", amount, ctx.accounts.destination.key());
+            Ok(())
+        }
+
+        /// Admin function to set the share of a stake's lock multiplier bonus forfeited on
+        /// emergency unstake (0 = keep all, 10_000 = back to 1x). That share of the bonus is
+        /// held back on each entry from now on and paid out when its lock ends, so claiming
+        /// first does not dodge the forfeit. Forfeited rewards stay in the reward vault for
+        /// other stakers.
+        pub fn set_bonus_forfeit(ctx: Context<SetBonusForfeit>, forfeit_bps: u32) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(forfeit_bps <= BPS_DENOMINATOR, StakingError::InvalidBonusForfeit);
+
+            pool.bonus_forfeit_bps = forfeit_bps;
+
+            msg!("Emergency unstake forfeits {} bps of the lock bonus", forfeit_bps);
+            Ok(())
+        }
+
+        /// Admin function to set the cooldown, in seconds, between `request_unlock` and
+        /// `withdraw_unlocked`. Zero disables cooldown exits; queued requests keep their time.
+        pub fn set_unlock_cooldown(ctx: Context<SetUnlockCooldown>, cooldown: u32) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(
+                cooldown as i64 <= get_lock_duration(LockPeriod::SixMonths),
+                StakingError::InvalidUnlockCooldown
+            );
+
+            pool.unlock_cooldown = cooldown;
+
+            msg!("Unlock cooldown set to {} seconds", cooldown);
+            Ok(())
+        }
+
+        /// Admin function to scale emissions by participation: rewards run at `max_bps` with
+        /// nothing staked, 1x at `target_total_staked` and `min_bps` from twice the target.
+        /// A zero target turns the curve off.
+        pub fn set_utilization_curve(
+            ctx: Context<SetUtilizationCurve>,
+            target_total_staked: u64,
+            min_bps: u32,
+            max_bps: u32,
+        ) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(
+                target_total_staked == 0
+                    || (min_bps <= BPS_DENOMINATOR && (BPS_DENOMINATOR..=MAX_UTILIZATION_BPS).contains(&max_bps)),
+                StakingError::InvalidUtilizationCurve
+            );
+
+            let current_time = Clock::get()?.unix_timestamp;
+            if pool.is_utilization_curve_on() {
+                // Close out accrual under the old curve
+                pool.checkpoint_utilization(current_time);
+            } else {
+                pool.utilization_start = current_time;
+                pool.utilization_updated_at = current_time;
+                pool.utilization_acc = 0;
+            }
+
+            pool.utilization_target = target_total_staked;
+            pool.utilization_min_bps = min_bps;
+            pool.utilization_max_bps = max_bps;
+
+            msg!(
+                "Utilization curve set: target {}, {}-{} bps, now {} bps",
+                target_total_staked, min_bps, max_bps, pool.utilization_bps()
+            );
+            Ok(())
+        }
+
+        /// Admin function to set the emission schedule: either a halving curve (initial rate
+        /// halved every `halving_interval` down to `floor_rate`) or a piecewise table of rates.
+        /// The flat `reward_rate` applies before `start_time`. A schedule cannot be replaced
+        /// once it has started, so rewards accrued under it stay exact.
+        pub fn set_emission_schedule(ctx: Context<SetEmissionSchedule>, params: EmissionScheduleParams) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+
+            let current_time = Clock::get()?.unix_timestamp;
+            require!(
+                !pool.emission.is_set() || pool.emission.start_time > current_time,
+                StakingError::EmissionScheduleStarted
+            );
+            require!(params.start_time >= current_time, StakingError::InvalidEmissionSchedule);
+
+            pool.emission = params.to_schedule()?;
+
+            msg!(
+                "Emission schedule set from {}: {} segments, initial rate {}, halving every {}s, floor {}",
+                params.start_time, params.segments.len(), params.initial_rate, params.halving_interval, params.floor_rate
+            );
+            Ok(())
+        }
+
+        /// Admin function to schedule a reward multiplier window for the lock tiers in the
+        /// `tiers` bitmask (bit n = LockPeriod n). Only accrual inside the window is boosted.
+        pub fn schedule_boost_window(
+            ctx: Context<ScheduleBoostWindow>,
+            start: i64,
+            end: i64,
+            multiplier_bps: u32, // 20_000 = 2x
+            tiers: u8,
+        ) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+
+            let current_time = Clock::get()?.unix_timestamp;
+            require!(start >= current_time && end > start, StakingError::InvalidBoostWindow);
+            require!(
+                multiplier_bps > BPS_DENOMINATOR && multiplier_bps <= MAX_WINDOW_MULTIPLIER_BPS,
+                StakingError::InvalidBoostWindow
+            );
+            require!(tiers != 0 && tiers & !ALL_TIERS == 0, StakingError::InvalidBoostWindow);
+
+            pool.prune_boost_windows(current_time);
+            pool.push_boost_window(BoostWindow {
+                start,
+                end,
+                multiplier_bps,
+                tiers,
+                padding: [0; 3],
+            })?;
+
+            msg!(
+                "Boost window scheduled: {} bps from {} to {} for tiers {:#06b}",
+                multiplier_bps, start, end, tiers
+            );
+            Ok(())
+        }
+
+        /// Admin function to cancel a boost window that has not started yet
+        pub fn cancel_boost_window(ctx: Context<ScheduleBoostWindow>, index: u8) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+
+            let index = index as usize;
+            require!(index < pool.boost_windows().len(), StakingError::InvalidBoostWindow);
+            // Rewards already accrued inside a window must stay exact
+            require!(
+                pool.boost_windows[index].start > Clock::get()?.unix_timestamp,
+                StakingError::BoostWindowStarted
+            );
+
+            pool.remove_boost_window(index);
+
+            msg!("Boost window {} cancelled", index);
+            Ok(())
+        }
+
+        /// Admin function to create the pool's NFT boost configuration
+        pub fn initialize_boost_config(ctx: Context<InitializeBoostConfig>) -> Result<()> {
+            require!(
+                ctx.accounts.authority.key() == ctx.accounts.staking_pool.load()?.authority,
+                StakingError::Unauthorized
+            );
+
+            let mut boost_config = ctx.accounts.boost_config.load_init()?;
+            boost_config.pool = ctx.accounts.staking_pool.key();
+            boost_config.bump = ctx.bumps.boost_config;
+
+            msg!("Boost config initialized: {}", ctx.accounts.boost_config.key());
+            Ok(())
+        }
+
+        /// Admin function to set the reward bonus, in basis points, for holders of an NFT from
+        /// a verified Metaplex collection. A zero bonus removes the collection.
+        pub fn set_boost_collection(ctx: Context<SetBoostCollection>, collection: Pubkey, bonus_bps: u16) -> Result<()> {
+            require!(
+                ctx.accounts.authority.key() == ctx.accounts.staking_pool.load()?.authority,
+                StakingError::Unauthorized
+            );
+            require!(bonus_bps <= MAX_BOOST_BPS, StakingError::InvalidBoost);
+
+            ctx.accounts.boost_config.load_mut()?.set_bonus(collection, bonus_bps)?;
+
+            msg!("Collection {} boost set to {} bps", collection, bonus_bps);
+            Ok(())
+        }
+
+        /// Boost the signer's rewards while they hold an NFT from a boosted collection.
+        /// The NFT is proven by its token account and Metaplex metadata.
+        pub fn apply_boost(ctx: Context<ApplyBoost>) -> Result<()> {
+            let pool = ctx.accounts.staking_pool.load()?;
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            require!(!pool.is_paused(), StakingError::PoolPaused);
+            require!(ctx.accounts.nft_mint.supply == 1, StakingError::NotAnNft);
+
+            let collection = boost::verified_collection(
+                &ctx.accounts.nft_metadata.to_account_info(),
+                &ctx.accounts.nft_mint.key(),
+            )?;
+            let bonus_bps = ctx.accounts.boost_config.load()?.bonus_for(&collection);
+            require!(bonus_bps > 0, StakingError::CollectionNotBoosted);
+
+            // Settle rewards at the previous rate before the boost takes effect
+            update_user_rewards(&mut user_account, &pool, Clock::get()?.unix_timestamp)?;
+
+            user_account.boost_bps = bonus_bps;
+            user_account.boost_token_account = ctx.accounts.nft_token_account.key();
+            user_account.boost_mint = ctx.accounts.nft_mint.key();
+            user_account.boost_collection = collection;
+
+            emit!(BoostEvent {
+                user: ctx.accounts.authority.key(),
+                collection,
+                bonus_bps,
+            });
+
+            msg!("Applied {} bps boost from collection {}", bonus_bps, collection);
+            Ok(())
+        }
+
+        /// Drop a boost whose NFT has left the wallet, or re-price it after the collection's
+        /// bonus changed. Anyone can call this.
+        pub fn refresh_boost(ctx: Context<RefreshBoost>) -> Result<()> {
+            let pool = ctx.accounts.staking_pool.load()?;
+            let mut user_account = ctx.accounts.user_account.load_mut()?;
+            require!(user_account.is_boosted(), StakingError::NoActiveBoost);
+
+            let still_holds_nft = boost::still_holds_nft(
+                &ctx.accounts.nft_token_account,
+                &user_account.authority,
+                &user_account.boost_mint,
+            );
+            let bonus_bps = if still_holds_nft {
+                ctx.accounts.boost_config.load()?.bonus_for(&user_account.boost_collection)
+            } else {
+                0
+            };
+
+            if bonus_bps == user_account.boost_bps {
+                msg!("Boost for {} is still valid", user_account.authority);
+                return Ok(());
+            }
+
+            // Settle rewards at the boosted rate up to now
+            update_user_rewards(&mut user_account, &pool, Clock::get()?.unix_timestamp)?;
+
+            let collection = user_account.boost_collection;
+            user_account.boost_bps = bonus_bps;
+            if bonus_bps == 0 {
+                user_account.boost_token_account = Pubkey::default();
+                user_account.boost_mint = Pubkey::default();
+                user_account.boost_collection = Pubkey::default();
+            }
+
+            emit!(BoostEvent {
+                user: user_account.authority,
+                collection,
+                bonus_bps,
+            });
+
+            msg!("Boost for {} refreshed to {} bps", user_account.authority, bonus_bps);
+            Ok(())
+        }
+
+        /// Admin function to configure launch caps. Zero disables a cap. Tier capacities
+        /// need complete per-tier totals, so on a migrated pool every user account holding
+        /// stake must be migrated first.
+        pub fn set_stake_caps(
+            ctx: Context<SetStakeCaps>,
+            max_stake_per_user: u64,
+            max_total_staked: u64,
+            tier_capacity: [u64; 4], // Max staked per LockPeriod
+        ) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(
+                tier_capacity == [0; 4] || pool.unmigrated_staked == 0,
+                StakingError::TierBackfillPending
+            );
+
+            pool.max_stake_per_user = max_stake_per_user;
+            pool.max_total_staked = max_total_staked;
+            pool.tier_capacity = tier_capacity;
+
+            msg!(
+                "Stake caps set: {} per user, {} total, tiers {:?}",
+                max_stake_per_user, max_total_staked, tier_capacity
+            );
+            Ok(())
+        }
+
+        /// Admin function to gate staking behind a Merkle allowlist. An all-zero root opens the pool.
+        pub fn set_allowlist_root(ctx: Context<SetAllowlistRoot>, allowlist_root: [u8; 32]) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+
+            pool.allowlist_root = allowlist_root;
+
+            if pool.is_allowlisted() {
+                msg!("Allowlist root set: {:?}", allowlist_root);
+            } else {
+                msg!("Allowlist cleared, staking is open");
+            }
+            Ok(())
+        }
+
+        /// Admin function to replace the list of Token-2022 mints allowed to carry extensions
+        /// that are otherwise rejected (permanent delegate, transfer hook, close authority).
+        /// Mints are checked when stakers deposit or claim, so a reviewed mint can be allowed
+        /// without a redeploy.
+        pub fn set_extension_allowlist(ctx: Context<SetExtensionAllowlist>, mints: Vec<Pubkey>) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+            require!(mints.len() <= MAX_EXTENSION_ALLOWLIST, StakingError::ExtensionAllowlistFull);
+
+            pool.extension_allowlist = [Pubkey::default(); MAX_EXTENSION_ALLOWLIST];
+            pool.extension_allowlist[..mints.len()].copy_from_slice(&mints);
+            pool.extension_allowlist_count = mints.len() as u8;
+
+            msg!("Extension allowlist set to {} mints", mints.len());
+            Ok(())
+        }
+
+        /// Read-only view of pool totals, minimums and caps
+        pub fn pool_info(ctx: Context<PoolInfoView>) -> Result<PoolInfo> {
+            let pool = ctx.accounts.staking_pool.load()?;
+
+            Ok(PoolInfo {
+                total_staked: pool.total_staked,
+                flexible_staked: pool.flexible_staked,
+                reward_rate: pool.reward_rate,
+                paused: pool.is_paused(),
+                min_stakes: pool.min_stakes,
+                max_stake_per_user: pool.max_stake_per_user,
+                max_total_staked: pool.max_total_staked,
+                tier_staked: pool.tier_staked,
+                unmigrated_staked: pool.unmigrated_staked,
+                tier_capacity: pool.tier_capacity,
+                allowlist_root: pool.allowlist_root,
+                emission_rate: pool.emission_rate_at(Clock::get()?.unix_timestamp),
+                utilization_bps: pool.utilization_bps(),
+            })
+        }
+
+        /// Read-only view of the emission rate in effect at `timestamp`
+        pub fn emission_rate_at(ctx: Context<PoolInfoView>, timestamp: i64) -> Result<u64> {
+            Ok(ctx.accounts.staking_pool.load()?.emission_rate_at(timestamp))
+        }
+
+        /// Admin function to pause/unpause the pool
+        pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+            let mut pool = ctx.accounts.staking_pool.load_mut()?;
+            require!(ctx.accounts.authority.key() == pool.authority, StakingError::Unauthorized);
+        
+            pool.paused = paused as u8;
+            msg!("Pool paused status set to: {}", paused);
+            Ok(())
+        }
+    }
+}
+
+pub use program_entry::*;
+
+// Helper functions
+fn get_lock_multiplier(lock_period: LockPeriod) -> u64 {
+    match lock_period {
+        LockPeriod::OneDay => 1000,     // 1.0x (scaled by 1000)
+        LockPeriod::OneWeek => 1250,    // 1.25x
+        LockPeriod::ThreeMonths => 2000, // 2.0x
+        LockPeriod::SixMonths => 3000,   // 3.0x
+    }
+}
+
+fn get_lock_duration(lock_period: LockPeriod) -> i64 {
+    match lock_period {
+        LockPeriod::OneDay => 86400,        // 1 day
+        LockPeriod::OneWeek => 604800,      // 7 days
+        LockPeriod::ThreeMonths => 7776000, // 90 days
+        LockPeriod::SixMonths => 15552000,  // 180 days
+    }
+}
+
+fn get_min_stake(pool: &StakingPool, lock_period: LockPeriod) -> u64 {
+    pool.min_stakes[lock_period as usize]
+}
+
+/// Check a new stake of `amount` against the per-user, pool-wide and per-tier caps.
+/// Flexible stakes have no lock period and only count toward the first two.
+fn validate_stake_caps(pool: &StakingPool, user_staked: u64, lock_period: Option<LockPeriod>, amount: u64) -> Result<()> {
+    require!(
+        pool.max_stake_per_user == 0 || user_staked.saturating_add(amount) <= pool.max_stake_per_user,
+        StakingError::UserCapExceeded
+    );
+    require!(
+        pool.max_total_staked == 0 || pool.total_staked.saturating_add(amount) <= pool.max_total_staked,
+        StakingError::PoolCapExceeded
+    );
+
+    if let Some(lock_period) = lock_period {
+        let tier = lock_period as usize;
+        require!(
+            pool.tier_capacity[tier] == 0 || pool.tier_staked[tier].saturating_add(amount) <= pool.tier_capacity[tier],
+            StakingError::TierCapacityExceeded
+        );
+    }
+    Ok(())
+}
+
+/// Check compounding the pending rewards into `stake_index` against the stake caps.
+/// Compounded rewards count toward the caps like a new stake of the entry's tier.
+fn validate_compound_caps(pool: &StakingPool, user_account: &UserAccount, stake_index: usize) -> Result<()> {
+    let lock_period = user_account.stakes()[stake_index].lock_period()?;
+    validate_stake_caps(pool, user_account.total_staked, Some(lock_period), user_account.pending_rewards)
+}
+
+/// Move an entry's principal between lock tiers, checking the destination tier's capacity
+fn move_tier_stake(pool: &mut StakingPool, from: LockPeriod, to: LockPeriod, amount: u64) -> Result<()> {
+    if from == to {
+        return Ok(());
+    }
+
+    let (from, to) = (from as usize, to as usize);
+    require!(
+        pool.tier_capacity[to] == 0 || pool.tier_staked[to].saturating_add(amount) <= pool.tier_capacity[to],
+        StakingError::TierCapacityExceeded
+    );
+    pool.remove_tier_stake(from, amount)?;
+    pool.tier_staked[to] += amount;
+    Ok(())
+}
+
+/// Shared body of `stake` and `stake_allowlisted`. `address_cap` is the allowlist
+/// leaf's per-address cap (0 = uncapped).
+fn process_stake(ctx: Context<Stake>, amount: u64, lock_period: LockPeriod, address_cap: u64) -> Result<()> {
+    let mut pool = ctx.accounts.staking_pool.load_mut()?;
+    let clock = Clock::get()?;
+
+    // A freshly created account has no discriminator until the instruction exits
+    let is_new_account = ctx.accounts.user_account.as_ref().try_borrow_data()?[..8]
+        .iter()
+        .all(|byte| *byte == 0);
+    let mut user_account = if is_new_account {
+        let mut user_account = ctx.accounts.user_account.load_init()?;
+        init_user_account(
+            &mut user_account,
+            ctx.accounts.authority.key(),
+            ctx.bumps.user_account,
+            clock.unix_timestamp,
+        );
+        msg!("User staking account initialized for: {}", ctx.accounts.authority.key());
+        user_account
+    } else {
+        ctx.accounts.user_account.load_mut()?
+    };
+
+    require!(!pool.is_paused(), StakingError::PoolPaused);
+    require!(amount > 0, StakingError::InvalidAmount);
+
+    // Security validations
+    SecurityValidator::validate_mint_extensions(&ctx.accounts.staking_mint.to_account_info(), pool.extension_allowlist())?;
+    SecurityValidator::validate_flash_loan_protection(&user_account, clock.unix_timestamp)?;
+    SecurityValidator::validate_rate_limiting(&user_account, clock.unix_timestamp, OperationType::Stake)?;
+    SecurityValidator::validate_account_consistency(&user_account)?;
+    SecurityValidator::validate_lock_period_gaming(user_account.stakes(), lock_period, clock.unix_timestamp)?;
+    SecurityValidator::validate_sybil_protection(&user_account, amount, token_unit(&pool), clock.unix_timestamp)?;
+
+    // Validate minimum stake amounts
+    require!(amount >= get_min_stake(&pool, lock_period), StakingError::BelowMinimumStake);
+
+    // Max 10 concurrent stakes per user
+    require!(user_account.stakes().len() < MAX_STAKES, StakingError::TooManyStakes);
+
+    // Launch caps: per user, pool-wide and per lock tier
+    validate_stake_caps(&pool, user_account.total_staked, Some(lock_period), amount)?;
+    require!(
+        address_cap == 0 || user_account.total_staked.saturating_add(amount) <= address_cap,
+        StakingError::AllowlistCapExceeded
+    );
+
+    // Update rewards before modifying stake
+    update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
+
+    // Transfer tokens to vault, crediting only what arrives after any transfer fee
+    let received = deposit_to_vault(
+        &ctx.accounts.user_token_account,
+        &mut ctx.accounts.staking_vault,
+        &ctx.accounts.staking_mint,
+        ctx.accounts.authority.to_account_info(),
+        &ctx.accounts.token_program,
+        &[],
+        amount,
+    )?;
+    require!(received >= get_min_stake(&pool, lock_period), StakingError::BelowMinimumStake);
+
+    // Create new stake entry
+    let stake_entry = StakeEntry::new(
+        received,
+        lock_period,
+        clock.unix_timestamp,
+        &pool.penalty_configs[lock_period as usize],
+    );
+    let lock_end = stake_entry.lock_end;
+    let multiplier = stake_entry.multiplier;
+    user_account.push_stake(stake_entry)?;
+
+    // Update totals
+    user_account.total_staked += received;
+    user_account.track_loyalty(clock.unix_timestamp);
+    pool.add_staked(received, clock.unix_timestamp);
+    pool.tier_staked[lock_period as usize] += received;
+
+    emit!(StakeEvent {
+        user: ctx.accounts.authority.key(),
+        amount: received,
+        lock_period,
+        lock_end,
+        multiplier,
+    });
+
+    msg!("Staked {} tokens with {:?} lock period", received, lock_period);
+    Ok(())
+}
+
+/// Shared body of `unstake` and `unstake_partial`: withdraw all of an expired entry, or `amount` of it
+fn process_unstake(ctx: Context<Unstake>, stake_index: u8, amount: Option<u64>) -> Result<()> {
+    let mut user_account = ctx.accounts.user_account.load_mut()?;
+    let clock = Clock::get()?;
+
+    // The pool signs the transfer below, so it cannot stay borrowed across the CPI
+    let (pool_bump, amount) = {
+        let pool = ctx.accounts.staking_pool.load()?;
+        require!(!pool.is_paused(), StakingError::PoolPaused);
+        require!((stake_index as usize) < user_account.stakes().len(), StakingError::InvalidStakeIndex);
+
+        // Update rewards before unstaking, rolling over auto-renewing entries
+        update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
+
+        let stake = &user_account.stakes()[stake_index as usize];
+        require!(stake.is_active(), StakingError::StakeNotActive);
+        require!(clock.unix_timestamp >= stake.lock_end, StakingError::StillLocked);
+        (pool.bump, withdrawal_amount(&pool, stake, amount)?)
+    };
+
+    let stake = &mut user_account.stakes_mut()[stake_index as usize];
+    let tier = stake.lock_period()? as usize;
+
+    // Transfer tokens back to user
+    let seeds = &[
+        STAKING_POOL_SEED.as_bytes(),
+        &[pool_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    let cpi_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            from: ctx.accounts.staking_vault.to_account_info(),
+            mint: ctx.accounts.staking_mint.to_account_info(),
+            to: ctx.accounts.user_token_account.to_account_info(),
+            authority: ctx.accounts.staking_pool.to_account_info(),
+        },
+        signer,
+    );
+    token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.staking_mint.decimals)?;
+
+    // Shrink the entry, or mark it inactive once empty
+    withdraw_from_entry(stake, amount);
+
+    // Update totals
+    user_account.total_staked -= amount;
+    user_account.track_loyalty(clock.unix_timestamp);
+    let mut pool = ctx.accounts.staking_pool.load_mut()?;
+    pool.remove_staked(amount, clock.unix_timestamp);
+    pool.remove_tier_stake(tier, amount)?;
+
+    emit!(UnstakeEvent {
+        user: ctx.accounts.authority.key(),
+        amount,
+        penalty: 0,
+    });
+
+    msg!("Unstaked {} tokens", amount);
+    Ok(())
+}
+
+/// Shared body of `emergency_unstake` and `emergency_unstake_partial`: break the lock on all
+/// of an entry, or `amount` of it
+fn process_emergency_unstake(ctx: Context<EmergencyUnstake>, stake_index: u8, amount: Option<u64>) -> Result<()> {
+    let mut user_account = ctx.accounts.user_account.load_mut()?;
+    let clock = Clock::get()?;
+
+    // The pool signs the transfers below, so it cannot stay borrowed across the CPIs
+    let (pool_bump, staked_amount) = {
+        let pool = ctx.accounts.staking_pool.load()?;
+        require!(!pool.is_paused(), StakingError::PoolPaused);
+        require!((stake_index as usize) < user_account.stakes().len(), StakingError::InvalidStakeIndex);
+
+        // Security validations
+        SecurityValidator::validate_rate_limiting(&user_account, clock.unix_timestamp, OperationType::EmergencyUnstake)?;
+        SecurityValidator::validate_account_consistency(&user_account)?;
+
+        // Update rewards before unstaking, rolling over auto-renewing entries
+        update_user_rewards(&mut user_account, &pool, clock.unix_timestamp)?;
+
+        let stake = &user_account.stakes()[stake_index as usize];
+        require!(stake.is_active(), StakingError::StakeNotActive);
+        require!(clock.unix_timestamp < stake.lock_end, StakingError::LockExpired);
+        let amount = withdrawal_amount(&pool, stake, amount)?;
+
+        forfeit_lock_bonus(&mut user_account, stake_index, amount)?;
+        (pool.bump, amount)
+    };
+
+    let stake = &mut user_account.stakes_mut()[stake_index as usize];
+    let tier = stake.lock_period()? as usize;
+    
+    // Progressive penalty on the terms locked in with the stake
+    let penalty_bps = stake.penalty_bps(clock.unix_timestamp)?;
+    
+    // Validate penalty calculation
+    SecurityValidator::validate_penalty_calculation(stake, clock.unix_timestamp, penalty_bps)?;
+    
+    let penalty_amount = scale_bps(staked_amount, penalty_bps as u32)?;
+    let return_amount = staked_amount - penalty_amount;
+
+    // Distribute penalty between burn, rewards pool and treasury on the stake's split
+    let burn_amount = scale_bps(penalty_amount, stake.penalty.burn_bps as u32)?;
+    let rewards_amount = scale_bps(penalty_amount, stake.penalty.rewards_bps as u32)?;
+    let treasury_amount = penalty_amount - burn_amount - rewards_amount;
+
+    let seeds = &[
+        STAKING_POOL_SEED.as_bytes(),
+        &[pool_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    // Transfer return amount to user
+    let cpi_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            from: ctx.accounts.staking_vault.to_account_info(),
+            mint: ctx.accounts.staking_mint.to_account_info(),
+            to: ctx.accounts.user_token_account.to_account_info(),
+            authority: ctx.accounts.staking_pool.to_account_info(),
+        },
+        signer,
+    );
+    token_interface::transfer_checked(cpi_ctx, return_amount, ctx.accounts.staking_mint.decimals)?;
+
+    // Burn the burn share. The rewards share stays in the vault, counted in penalty_rewards.
+    if burn_amount > 0 {
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            Burn {
+                mint: ctx.accounts.staking_mint.to_account_info(),
+                from: ctx.accounts.staking_vault.to_account_info(),
+                authority: ctx.accounts.staking_pool.to_account_info(),
+            },
+            signer,
+        );
+        token_interface::burn(cpi_ctx, burn_amount)?;
+        msg!("Burned {} penalty tokens", burn_amount);
+    }
+
+    if treasury_amount > 0 {
+        // Transfer to treasury
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.staking_vault.to_account_info(),
+                mint: ctx.accounts.staking_mint.to_account_info(),
+                to: ctx.accounts.treasury_account.to_account_info(),
+                authority: ctx.accounts.staking_pool.to_account_info(),
+            },
+            signer,
+        );
+        token_interface::transfer_checked(cpi_ctx, treasury_amount, ctx.accounts.staking_mint.decimals)?;
+    }
+
+    // Shrink the entry, or mark it inactive once empty
+    withdraw_from_entry(stake, staked_amount);
+
+    // Update totals
+    user_account.total_staked -= staked_amount;
+    let mut pool = ctx.accounts.staking_pool.load_mut()?;
+    pool.remove_staked(staked_amount, clock.unix_timestamp);
+    pool.remove_tier_stake(tier, staked_amount)?;
+    if rewards_amount > 0 {
+        pool.penalty_rewards += rewards_amount;
+        msg!("Added {} to penalty rewards", rewards_amount);
+    }
+
+    // Breaking a lock early costs loyalty
+    user_account.decay_loyalty(pool.loyalty_decay_bps, clock.unix_timestamp);
+    user_account.track_loyalty(clock.unix_timestamp);
+
+    emit!(UnstakeEvent {
+        user: ctx.accounts.authority.key(),
+        amount: return_amount,
+        penalty: penalty_amount,
+    });
+
+    msg!("Emergency unstaked {} tokens with {} bps penalty ({})", 
+        return_amount, penalty_bps, penalty_amount);
+    Ok(())
+}
+
+/// Withdraw every active entry whose lock has ended with a single transfer, returning
+/// the total and the entries taken. Rewards are checkpointed even when none has expired.
+fn unstake_expired_entries<'info>(
+    pool_loader: &AccountLoader<'info, StakingPool>,
+    user_account: &mut UserAccount,
+    staking_vault: &InterfaceAccount<'info, TokenAccount>,
+    user_token_account: &InterfaceAccount<'info, TokenAccount>,
+    staking_mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    current_time: i64,
+) -> Result<(u64, Vec<UnstakedEntry>)> {
+    // The pool signs the transfer below, so it cannot stay borrowed across the CPI
+    let pool_bump = {
+        let pool = pool_loader.load()?;
+        require!(!pool.is_paused(), StakingError::PoolPaused);
+
+        // Update rewards before unstaking, rolling over auto-renewing entries
+        update_user_rewards(user_account, &pool, current_time)?;
+        pool.bump
+    };
+
+    let mut entries = Vec::new();
+    let mut tier_amounts = [0u64; 4];
+    for (index, stake) in user_account.stakes_mut().iter_mut().enumerate() {
+        if stake.is_active() && current_time >= stake.lock_end {
+            tier_amounts[stake.lock_period()? as usize] += stake.amount;
+            stake.is_active = 0;
+            entries.push(UnstakedEntry {
+                stake_index: index as u8,
+                amount: stake.amount,
+            });
+        }
+    }
+    if entries.is_empty() {
+        return Ok((0, entries));
+    }
+    let amount: u64 = tier_amounts.iter().sum();
+
+    let seeds = &[
+        STAKING_POOL_SEED.as_bytes(),
+        &[pool_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        TransferChecked {
+            from: staking_vault.to_account_info(),
+            mint: staking_mint.to_account_info(),
+            to: user_token_account.to_account_info(),
+            authority: pool_loader.to_account_info(),
+        },
+        signer,
+    );
+    token_interface::transfer_checked(cpi_ctx, amount, staking_mint.decimals)?;
+
+    // Update totals
+    user_account.total_staked -= amount;
+    user_account.track_loyalty(current_time);
+    let mut pool = pool_loader.load_mut()?;
+    pool.remove_staked(amount, current_time);
+    for (tier, tier_amount) in tier_amounts.into_iter().enumerate() {
+        pool.remove_tier_stake(tier, tier_amount)?;
+    }
+
+    Ok((amount, entries))
+}
+
+/// Amount to take out of `stake`: all of it, or `amount` if what stays still meets
+/// the tier minimum
+fn withdrawal_amount(pool: &StakingPool, stake: &StakeEntry, amount: Option<u64>) -> Result<u64> {
+    let Some(amount) = amount else {
+        return Ok(stake.amount);
+    };
+
+    require!(amount > 0 && amount <= stake.amount, StakingError::InvalidAmount);
+    let remainder = stake.amount - amount;
+    require!(
+        remainder == 0 || remainder >= get_min_stake(pool, stake.lock_period()?),
+        StakingError::BelowMinimumStake
+    );
+    Ok(amount)
+}
+
+/// Take `amount` out of an entry. Taking all of it deactivates the entry and keeps
+/// the amount on record.
+fn withdraw_from_entry(stake: &mut StakeEntry, amount: u64) {
+    if amount == stake.amount {
+        stake.is_active = 0;
+    } else {
+        stake.amount -= amount;
+    }
+}
+
+/// One whole staking token in base units
+fn token_unit(pool: &StakingPool) -> u64 {
+    10u64.pow(pool.staking_decimals as u32)
+}
+
+/// Scale the whole-token minimums to the staking mint's decimals
+fn scale_min_stakes(decimals: u8) -> Result<[u64; 4]> {
+    let unit = 10u64
+        .checked_pow(decimals as u32)
+        .ok_or(StakingError::InvalidMintDecimals)?;
+    let scale = |whole_tokens: u64| {
+        whole_tokens
+            .checked_mul(unit)
+            .ok_or(StakingError::InvalidMintDecimals)
+    };
+    Ok([
+        scale(MIN_STAKE_24H)?,
+        scale(MIN_STAKE_1W)?,
+        scale(MIN_STAKE_3M)?,
+        scale(MIN_STAKE_6M)?,
+    ])
+}
+
+/// Fill in a newly created user account
+fn init_user_account(user_account: &mut UserAccount, authority: Pubkey, bump: u8, current_time: i64) {
+    user_account.authority = authority;
+    user_account.total_staked = 0;
+    user_account.pending_rewards = 0;
+    user_account.last_reward_time = current_time;
+    user_account.bump = bump;
+    user_account.version = USER_ACCOUNT_VERSION;
+}
+
+/// Start a fresh lock of the given tier on an existing entry, keeping its principal in the vault
+fn relock_entry(stake: &mut StakeEntry, pool: &StakingPool, lock_period: LockPeriod, current_time: i64) {
+    stake.lock_period = lock_period as u8;
+    stake.lock_start = current_time;
+    stake.lock_end = current_time + get_lock_duration(lock_period);
+    stake.multiplier = get_lock_multiplier(lock_period);
+    stake.penalty = pool.penalty_configs[lock_period as usize];
+}
+
+/// Renew an expired entry in its tier for as many back-to-back locks as have ended,
+/// so the current lock covers `current_time`
+fn roll_over_entry(stake: &mut StakeEntry, pool: &StakingPool, current_time: i64) -> Result<()> {
+    if current_time < stake.lock_end {
+        return Ok(());
+    }
+
+    let lock_period = stake.lock_period()?;
+    let duration = get_lock_duration(lock_period);
+    let ended_locks = (current_time - stake.lock_end) / duration;
+    relock_entry(stake, pool, lock_period, stake.lock_end + ended_locks * duration);
+    Ok(())
+}
+
+fn update_user_rewards(
+    user_account: &mut UserAccount,
+    pool: &StakingPool,
+    current_time: i64,
+) -> Result<()> {
+    let time_diff = current_time - user_account.last_reward_time;
+    if time_diff <= 0 {
+        user_account.utilization_snapshot = pool.utilization_acc_at(current_time);
+        return Ok(());
+    }
+
+    let mut total_rewards = 0u64;
+    let mut released_bonus = 0u64;
+    let loyalty_since = user_account.loyalty_since;
+    let last_reward_time = user_account.last_reward_time;
+    let auto_renew = user_account.auto_renew;
+
+    // Participation scaling, averaged over the accrual since the last checkpoint
+    let utilization_bps = if pool.is_utilization_curve_on() {
+        pool.average_utilization_bps(last_reward_time, user_account.utilization_snapshot, current_time)
+    } else {
+        BPS_DENOMINATOR
+    };
+    let boost_bps = user_account.boost_bps as u32;
+
+    // Calculate rewards for each active stake
+    for (index, stake) in user_account.stakes_mut().iter_mut().enumerate() {
+        if !stake.is_active() {
+            continue;
+        }
+
+        // Calculate effective staking time (only while locked or after unlock)
+        let stake_start = std::cmp::max(stake.lock_start, last_reward_time);
+
+        // A lock that ran its course pays out the bonus held back during it
+        let served_lock = current_time >= stake.lock_end;
+        if served_lock {
+            released_bonus += std::mem::take(&mut stake.locked_bonus);
+        }
+
+        // Renewed locks follow on from the old lock end, so accrual runs on without a gap
+        if auto_renew & (1 << index) != 0 {
+            roll_over_entry(stake, pool, current_time)?;
+        }
+        let stake_end = std::cmp::min(current_time, stake.lock_end);
+
+        // Only accrual inside the lock still running is held back. What accrued before a
+        // rollover belongs to locks that were served in full.
+        let held_start = if served_lock {
+            std::cmp::max(stake_start, stake.lock_start)
+        } else {
+            stake_start
+        };
+        
+        if stake_end > stake_start {
+            // Emission rate integrated over the stake's accrual, so rate changes are exact
+            let rate_seconds = pool.emission_integral(stake_start, stake_end);
+            let base_reward = accrued_reward(stake.amount, rate_seconds, stake.multiplier)?;
+
+            let stake_reward = base_reward + boost_window_bonus(pool, stake, stake_start, stake_end)?;
+
+            // Loyalty on top of the lock multiplier
+            let loyalty_scale_bps = BPS_DENOMINATOR + pool.average_loyalty_bps(loyalty_since, stake_start, stake_end);
+            let mut stake_reward = scale_bps(stake_reward, loyalty_scale_bps)?;
+
+            // The forfeitable share of the lock bonus waits for the lock to end
+            if current_time < stake.lock_end && stake_end > held_start {
+                let held_rate_seconds = pool.emission_integral(held_start, stake_end);
+                let held = scale_bps(held_lock_bonus(pool, stake, held_rate_seconds)?, loyalty_scale_bps)?;
+                stake_reward -= held;
+                stake.locked_bonus += scale_rewards(held, utilization_bps, boost_bps)?;
+            }
+            total_rewards += stake_reward;
+        }
+    }
+
+    let total_rewards = scale_rewards(total_rewards, utilization_bps, boost_bps)?
+        .checked_add(released_bonus)
+        .ok_or(StakingError::RewardCalculationError)?;
+
+    user_account.pending_rewards += total_rewards;
+    user_account.last_reward_time = current_time;
+    user_account.utilization_snapshot = pool.utilization_acc_at(current_time);
+    user_account.track_loyalty(current_time);
+
+    Ok(())
+}
+
+/// Extra rewards from boost windows for a stake's accrual between `start` and `end`.
+/// Only the overlap with each window is boosted, so the result does not depend on
+/// how accrual is split across checkpoints beyond per-call rounding.
+fn boost_window_bonus(pool: &StakingPool, stake: &StakeEntry, start: i64, end: i64) -> Result<u64> {
+    let tier_bit = 1u8 << stake.lock_period()? as u8;
+
+    // Emission inside each window, weighted by the window's bonus over 1x
+    let mut weighted_rate_seconds = 0u128;
+    for window in pool.boost_windows() {
+        if window.tiers & tier_bit == 0 {
+            continue;
+        }
+        let overlap_start = std::cmp::max(start, window.start);
+        let overlap_end = std::cmp::min(end, window.end);
+        if overlap_end > overlap_start {
+            weighted_rate_seconds += pool.emission_integral(overlap_start, overlap_end)
+                * (window.multiplier_bps - BPS_DENOMINATOR) as u128
+                / BPS_DENOMINATOR as u128;
+        }
+    }
+    if weighted_rate_seconds == 0 {
+        return Ok(0);
+    }
+
+    accrued_reward(stake.amount, weighted_rate_seconds, stake.multiplier)
+}
+
+/// Apply the participation scaling and then the NFT holder boost on top to `amount`
+fn scale_rewards(amount: u64, utilization_bps: u32, boost_bps: u32) -> Result<u64> {
+    let scaled = scale_bps(amount, utilization_bps)?;
+    scaled
+        .checked_add(scale_bps(scaled, boost_bps)?)
+        .ok_or(error!(StakingError::RewardCalculationError))
+}
+
+/// `amount` scaled by `bps` / 10_000
+fn scale_bps(amount: u64, bps: u32) -> Result<u64> {
+    let scaled = amount as u128 * bps as u128 / BPS_DENOMINATOR as u128;
+    u64::try_from(scaled).map_err(|_| error!(StakingError::RewardCalculationError))
+}
+
+/// Forfeit the lock bonus held back on the `amount` of `stake_index` leaving its lock
+/// early. Call after checkpointing rewards, so the hold-back covers accrual up to now.
+fn forfeit_lock_bonus(user_account: &mut UserAccount, stake_index: u8, amount: u64) -> Result<()> {
+    let user = user_account.authority;
+    let stake = &mut user_account.stakes_mut()[stake_index as usize];
+    let forfeit = (stake.locked_bonus as u128 * amount as u128 / stake.amount as u128) as u64;
+    if forfeit > 0 {
+        stake.locked_bonus -= forfeit;
+        emit!(RewardsForfeitedEvent {
+            user,
+            stake_index,
+            amount: forfeit,
+        });
+        msg!("Forfeited {} reward tokens back to the pool", forfeit);
+    }
+    Ok(())
+}
+
+/// The pool's forfeit share of what `stake`'s lock multiplier earns above 1x over
+/// `rate_seconds`, before loyalty and the other scalings
+fn held_lock_bonus(pool: &StakingPool, stake: &StakeEntry, rate_seconds: u128) -> Result<u64> {
+    let bonus_multiplier = stake.multiplier.saturating_sub(BASE_MULTIPLIER);
+    if pool.bonus_forfeit_bps == 0 || bonus_multiplier == 0 {
+        return Ok(0);
+    }
+
+    let bonus = accrued_reward(stake.amount, rate_seconds, bonus_multiplier)?;
+    scale_bps(bonus, pool.bonus_forfeit_bps)
+}
+
+/// Rewards for `amount` over `rate_seconds` (the emission rate integrated over time)
+/// at a lock multiplier scaled by 1000: (amount / 1000) * rate * days * multiplier
+fn accrued_reward(amount: u64, rate_seconds: u128, multiplier: u64) -> Result<u64> {
+    let reward = (amount as u128)
+        .checked_mul(rate_seconds)
+        .and_then(|v| v.checked_mul(multiplier as u128))
+        .map(|v| v / (86400 * 1000 * 1_000_000 * 1000))
+        .ok_or(StakingError::RewardCalculationError)?;
+    u64::try_from(reward).map_err(|_| error!(StakingError::RewardCalculationError))
+}
+
+/// Accrue base rate rewards on the flexible tier into the receipt exchange rate.
+/// Rewards can only back receipt tokens when they are paid in the staking token, which
+/// initialize_receipt_mint enforces.
+fn sync_flexible_rewards<'info>(
+    pool_loader: &AccountLoader<'info, StakingPool>,
+    reward_vault: &InterfaceAccount<'info, TokenAccount>,
+    staking_vault: &mut InterfaceAccount<'info, TokenAccount>,
+    staking_mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    current_time: i64,
+) -> Result<()> {
+    let (accrued, pool_bump) = {
+        let mut pool = pool_loader.load_mut()?;
+        let last_update = pool.flexible_last_update;
+        if current_time <= last_update {
+            return Ok(());
+        }
+        let utilization_snapshot = pool.flexible_utilization_snapshot;
+        pool.flexible_last_update = current_time;
+        pool.flexible_utilization_snapshot = pool.utilization_acc_at(current_time);
+
+        if pool.flexible_staked == 0 {
+            return Ok(());
+        }
+
+        // Base rate (1.0x multiplier) accrued since the last sync
+        let rate_seconds = pool.emission_integral(last_update, current_time);
+        let mut accrued = accrued_reward(pool.flexible_staked, rate_seconds, 1000)?;
+        if pool.is_utilization_curve_on() {
+            let utilization_bps = pool.average_utilization_bps(last_update, utilization_snapshot, current_time);
+            accrued = scale_bps(accrued, utilization_bps)?;
+        }
+        (std::cmp::min(accrued, reward_vault.amount), pool.bump)
+    };
+    if accrued == 0 {
+        return Ok(());
+    }
+
+    let seeds = &[
+        STAKING_POOL_SEED.as_bytes(),
+        &[pool_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    // Move accrued rewards into the staking vault so receipts stay fully backed
+    let received = deposit_to_vault(
+        reward_vault,
+        staking_vault,
+        staking_mint,
+        pool_loader.to_account_info(),
+        token_program,
+        signer,
+        accrued,
+    )?;
+
+    let mut pool = pool_loader.load_mut()?;
+    pool.flexible_staked += received;
+    pool.add_staked(received, current_time);
+
+    Ok(())
+}
+
+/// Checkpoint a user's rewards and pay everything pending from the reward vault
+fn claim_pending_rewards<'info>(
+    pool_loader: &AccountLoader<'info, StakingPool>,
+    user_account: &mut UserAccount,
+    reward_vault: &InterfaceAccount<'info, TokenAccount>,
+    destination: &InterfaceAccount<'info, TokenAccount>,
+    reward_mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    current_time: i64,
+) -> Result<u64> {
+    // Update rewards
+    let pool_bump = {
+        let pool = pool_loader.load()?;
+        SecurityValidator::validate_mint_extensions(&reward_mint.to_account_info(), pool.extension_allowlist())?;
+        update_user_rewards(user_account, &pool, current_time)?;
+        pool.bump
+    };
+
+    let reward_amount = user_account.pending_rewards;
+    require!(reward_amount > 0, StakingError::NoRewardsToClaim);
+
+    let seeds = &[
+        STAKING_POOL_SEED.as_bytes(),
+        &[pool_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    // Transfer $WePee rewards to destination
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        TransferChecked {
+            from: reward_vault.to_account_info(),
+            mint: reward_mint.to_account_info(),
+            to: destination.to_account_info(),
+            authority: pool_loader.to_account_info(),
+        },
+        signer,
+    );
+    token_interface::transfer_checked(cpi_ctx, reward_amount, reward_mint.decimals)?;
+
+    user_account.pending_rewards = 0;
+
+    Ok(reward_amount)
+}
+
+/// Move a user's pending rewards from the reward vault into the staking vault and
+/// add them to the principal of a still-locked stake entry
+#[allow(clippy::too_many_arguments)]
+fn compound_into_entry<'info>(
+    pool_loader: &AccountLoader<'info, StakingPool>,
+    user_account: &mut UserAccount,
+    stake_index: usize,
+    reward_vault: &InterfaceAccount<'info, TokenAccount>,
+    staking_vault: &mut InterfaceAccount<'info, TokenAccount>,
+    staking_mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    current_time: i64,
+) -> Result<u64> {
+    let (amount, pool_bump) = {
+        let pool = pool_loader.load()?;
+        require!(pool.reward_mint == pool.staking_mint, StakingError::CompoundMintMismatch);
+        require!(stake_index < user_account.stakes().len(), StakingError::InvalidStakeIndex);
+
+        // Update rewards before modifying stake, rolling over auto-renewing entries
+        update_user_rewards(user_account, &pool, current_time)?;
+
+        require!(user_account.stakes()[stake_index].is_active(), StakingError::StakeNotActive);
+        require!(current_time < user_account.stakes()[stake_index].lock_end, StakingError::LockExpired);
+
+        let amount = user_account.pending_rewards;
+        require!(amount >= MIN_COMPOUND_TOKENS * token_unit(&pool), StakingError::BelowMinimumCompound);
+        validate_compound_caps(&pool, user_account, stake_index)?;
+        (amount, pool.bump)
+    };
+
+    let seeds = &[
+        STAKING_POOL_SEED.as_bytes(),
+        &[pool_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    // Credit only what arrives after any transfer fee
+    let received = deposit_to_vault(
+        reward_vault,
+        staking_vault,
+        staking_mint,
+        pool_loader.to_account_info(),
+        token_program,
+        signer,
+        amount,
+    )?;
+
+    user_account.pending_rewards = 0;
+    let stake = &mut user_account.stakes_mut()[stake_index];
+    stake.amount += received;
+    let tier = stake.lock_period()? as usize;
+    user_account.total_staked += received;
+
+    let mut pool = pool_loader.load_mut()?;
+    pool.add_staked(received, current_time);
+    pool.tier_staked[tier] += received;
+
+    Ok(received)
+}
+
+/// Pick the active, still-locked entry with the latest lock end for auto-compounding
+fn select_auto_compound_entry(user_account: &UserAccount, current_time: i64) -> Option<usize> {
+    user_account.stakes().iter()
+        .enumerate()
+        .filter(|(_, stake)| stake.is_active() && stake.lock_end > current_time)
+        .max_by_key(|(_, stake)| stake.lock_end)
+        .map(|(index, _)| index)
+}
+
+/// Transfer into a vault and return the amount actually received, which is less than
+/// `amount` when a Token-2022 mint withholds a transfer fee
+fn deposit_to_vault<'info>(
+    from: &InterfaceAccount<'info, TokenAccount>,
+    vault: &mut InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    authority: AccountInfo<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<u64> {
+    let balance_before = vault.amount;
+
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        TransferChecked {
+            from: from.to_account_info(),
+            mint: mint.to_account_info(),
+            to: vault.to_account_info(),
+            authority,
+        },
+        signer_seeds,
+    );
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)?;
+
+    vault.reload()?;
+    let received = vault.amount
+        .checked_sub(balance_before)
+        .ok_or(StakingError::InvalidAmount)?;
+    require!(received > 0, StakingError::InvalidAmount);
+
+    Ok(received)
+}
+
+/// Tokens `receipt_amount` receipt tokens currently redeem for
+fn receipt_backed_amount(receipt_amount: u64, flexible_staked: u64, receipt_supply: u64) -> Result<u64> {
+    if receipt_amount == 0 {
+        return Ok(0);
+    }
+    calculate_redeem_amount(receipt_amount, flexible_staked, receipt_supply)
+}
+
+/// Receipt tokens minted for a flexible deposit at the current exchange rate
+fn calculate_receipt_amount(amount: u64, flexible_staked: u64, receipt_supply: u64) -> Result<u64> {
+    if receipt_supply == 0 || flexible_staked == 0 {
+        return Ok(amount);
+    }
+    let receipt_amount = (amount as u128)
+        .checked_mul(receipt_supply as u128)
+        .map(|v| v / flexible_staked as u128)
+        .ok_or(StakingError::InvalidAmount)?;
+    u64::try_from(receipt_amount).map_err(|_| error!(StakingError::InvalidAmount))
+}
+
+/// Underlying tokens redeemed for burning receipt tokens at the current exchange rate
+fn calculate_redeem_amount(receipt_amount: u64, flexible_staked: u64, receipt_supply: u64) -> Result<u64> {
+    require!(receipt_supply > 0, StakingError::InvalidAmount);
+    let amount = (receipt_amount as u128)
+        .checked_mul(flexible_staked as u128)
+        .map(|v| v / receipt_supply as u128)
+        .ok_or(StakingError::InvalidAmount)?;
+    u64::try_from(amount).map_err(|_| error!(StakingError::InvalidAmount))
+}
+
+// Constants
+const STAKING_POOL_SEED: &str = "staking_pool";
+const USER_ACCOUNT_SEED: &str = "user_account";
+const RECEIPT_MINT_SEED: &str = "receipt_mint";
+const STAKING_VAULT_SEED: &str = "staking_vault";
+const REWARD_VAULT_SEED: &str = "reward_vault";
+const BOOST_CONFIG_SEED: &str = "boost_config";
+const UNLOCK_QUEUE_SEED: &str = "unlock_queue";
+
+// Account layout versions and sizes
+const STAKING_POOL_VERSION: u8 = 1;
+const USER_ACCOUNT_VERSION: u8 = 1;
+const STAKING_POOL_SPACE: usize = 8 + std::mem::size_of::<StakingPool>();
+const USER_ACCOUNT_SPACE: usize = 8 + std::mem::size_of::<UserAccount>();
+const BOOST_CONFIG_SPACE: usize = 8 + std::mem::size_of::<BoostConfig>();
+const UNLOCK_QUEUE_SPACE: usize = 8 + std::mem::size_of::<UnlockQueue>();
+
+// Max concurrent stake entries per user
+pub const MAX_STAKES: usize = 10;
+
+// Max cooldown exits a user can have waiting at once
+pub const MAX_UNLOCK_REQUESTS: usize = 10;
+
+// Minimum pending rewards for a compound, keeps cranks from compounding dust
+const MIN_COMPOUND_TOKENS: u64 = 1; // Whole tokens
+
+// Delegate permission bits
+pub const DELEGATE_CLAIM: u8 = 1 << 0;
+pub const DELEGATE_EXTEND_LOCK: u8 = 1 << 1;
+pub const DELEGATE_COMPOUND: u8 = 1 << 2;
+pub const DELEGATE_RESTAKE: u8 = 1 << 3;
+const DELEGATE_ALL: u8 = DELEGATE_CLAIM | DELEGATE_EXTEND_LOCK | DELEGATE_COMPOUND | DELEGATE_RESTAKE;
+
+// NFT holder boosts
+pub const MAX_BOOST_COLLECTIONS: usize = 8;
+const MAX_BOOST_BPS: u16 = 10_000; // +100% rewards
+
+// Emission schedule
+pub const MAX_EMISSION_SEGMENTS: usize = 8;
+
+// Emergency unstake penalties
+pub const MAX_PENALTY_BPS: u16 = 5_000; // 50% of principal
+pub const DEFAULT_PENALTY: PenaltyConfig = PenaltyConfig {
+    max_bps: 3_300,
+    floor_bps: 0,
+    burn_bps: 4_000,
+    rewards_bps: 4_000,
+    curve: PenaltyCurve::Linear as u8,
+    padding: [0; 1],
+};
+const BASE_MULTIPLIER: u64 = 1000; // 1.0x, earned without a lock bonus
+
+// Loyalty bonus
+const MAX_LOYALTY_BPS: u32 = 10_000; // +100%
+
+// Participation-based emission scaling
+const MAX_UTILIZATION_BPS: u32 = 50_000; // 5x
+
+// Scheduled reward multiplier windows
+pub const MAX_BOOST_WINDOWS: usize = 8;
+pub const MAX_EXTENSION_ALLOWLIST: usize = 4;
+const BPS_DENOMINATOR: u32 = 10_000;
+const MAX_WINDOW_MULTIPLIER_BPS: u32 = 50_000; // 5x
+const ALL_TIERS: u8 = 0b1111;                  // One bit per LockPeriod
+const BOOST_WINDOW_RETENTION: i64 = 365 * 86400; // Ended windows kept for late checkpoints
+
+// Keeper crank limits
+const MAX_CRANK_BATCH: usize = 10;      // Max user accounts per crank
+const MIN_CRANK_INTERVAL: i64 = 3600;   // 1 hour minimum between crank services per user
+
+// Minimum stake amounts (in whole tokens, scaled by the mint's decimals at pool initialization)
+const MIN_STAKE_24H: u64 = 100;
+const MIN_STAKE_1W: u64 = 250;
+const MIN_STAKE_3M: u64 = 500;
+const MIN_STAKE_6M: u64 = 1000;
+
+// Account structures
+// Both accounts are zero-copy so instructions read fields in place instead of
+// deserializing the whole account. Fields are ordered largest-alignment first and
+// bools are stored as u8 to keep the layouts free of implicit padding.
+#[account(zero_copy)]
+pub struct StakingPool {
+    pub authority: Pubkey,
+    pub staking_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub staking_vault: Pubkey,
+    pub reward_vault: Pubkey,
+    pub receipt_mint: Pubkey,      // Liquid receipt token for flexible staking (default = disabled)
+    pub total_staked: u64,
+    pub reward_rate: u64, // Base $WePee per 1000 staked per day (scaled by 1e6)
+    pub flexible_staked: u64,      // Tokens backing outstanding receipt tokens
+    pub flexible_last_update: i64, // Last time base rate rewards were synced into flexible_staked
+    pub crank_bounty: u64,         // Reward tokens paid to keepers per entry compounded or rolled over
+    pub crank_interval: i64,       // Minimum seconds between crank services of the same user
+    pub crank_bounty_cap: u64,     // Max bounty per crank call
+    pub crank_bounty_budget: u64,  // Bounty keepers can still earn, drawn down by each payout
+    pub min_stakes: [u64; 4],      // Minimum stake per LockPeriod in base units
+    pub bump: u8,
+    pub paused: u8,
+    pub staking_decimals: u8,
+    pub version: u8,               // Layout version, see migrate_pool
+    pub staking_vault_bump: u8,    // Vault and receipt mint PDA bumps (0 for pools created before PDA vaults)
+    pub reward_vault_bump: u8,
+    pub receipt_mint_bump: u8,
+    pub padding: [u8; 1],
+    pub max_stake_per_user: u64,   // Launch caps in base units (0 = uncapped)
+    pub max_total_staked: u64,
+    pub tier_capacity: [u64; 4],   // Max staked per LockPeriod (0 = uncapped)
+    pub tier_staked: [u64; 4],     // Locked principal per LockPeriod
+    pub unmigrated_staked: u64,    // Principal of user accounts not yet migrated, missing from tier_staked
+    pub penalty_rewards: u64,      // Rewards share of emergency penalties, held in the staking vault
+    pub unlocking: u64,            // Requested unlocks still in the staking vault, owed to their stakers
+    pub allowlist_root: [u8; 32],  // Merkle root of allowed stakers (all zero = open)
+    pub extension_allowlist: [Pubkey; MAX_EXTENSION_ALLOWLIST], // Reviewed Token-2022 mints, first extension_allowlist_count in use
+    pub boost_windows: [BoostWindow; MAX_BOOST_WINDOWS], // Only the first boost_window_count are in use
+    pub boost_window_count: u8,
+    pub extension_allowlist_count: u8,
+    pub padding2: [u8; 6],
+    pub emission: EmissionSchedule, // Replaces the flat reward_rate from its start time
+    pub utilization_target: u64,   // total_staked earning 1x emissions (0 = curve off)
+    pub utilization_acc: u64,      // Utilization bps integrated over seconds since utilization_start
+    pub utilization_start: i64,    // When the curve was last turned on
+    pub utilization_updated_at: i64,
+    pub flexible_utilization_snapshot: u64, // utilization_acc at flexible_last_update
+    pub utilization_min_bps: u32,  // Scaling from twice the target up
+    pub utilization_max_bps: u32,  // Scaling with nothing staked
+    pub loyalty_rate_bps: u32,     // Loyalty bonus gained per day of uninterrupted stake (0 = off)
+    pub loyalty_cap_bps: u32,      // Maximum loyalty bonus
+    pub loyalty_decay_bps: u32,    // Share of loyalty time lost on emergency unstake
+    pub padding3: [u8; 4],
+    pub penalty_configs: [PenaltyConfig; 4], // Emergency unstake terms per LockPeriod
+    pub bonus_forfeit_bps: u32,    // Share of the lock multiplier bonus lost on emergency unstake
+    pub unlock_cooldown: u32,      // Seconds from request_unlock to withdraw_unlocked (0 = disabled)
+    pub reserved: [u8; 8],         // Padding for future fields without a realloc
+}
+
+impl StakingPool {
+    pub fn is_paused(&self) -> bool {
+        self.paused != 0
+    }
+
+    pub fn is_allowlisted(&self) -> bool {
+        self.allowlist_root != [0; 32]
+    }
+
+    pub fn is_utilization_curve_on(&self) -> bool {
+        self.utilization_target != 0
+    }
+
+    /// Emission scaling for the current total_staked, in basis points: max_bps with
+    /// nothing staked, 1x at the target, min_bps from twice the target
+    pub fn utilization_bps(&self) -> u32 {
+        if !self.is_utilization_curve_on() {
+            return BPS_DENOMINATOR;
+        }
+
+        let denominator = BPS_DENOMINATOR as u128;
+        let ratio = self.total_staked as u128 * denominator / self.utilization_target as u128;
+        let (min, max) = (self.utilization_min_bps as u128, self.utilization_max_bps as u128);
+        let bps = if ratio <= denominator {
+            max - (max - denominator) * ratio / denominator
+        } else if ratio < 2 * denominator {
+            denominator - (denominator - min) * (ratio - denominator) / denominator
+        } else {
+            min
+        };
+        bps as u32
+    }
+
+    /// utilization_acc brought forward to `timestamp`. total_staked has not changed since
+    /// the last checkpoint, so this is exact.
+    pub fn utilization_acc_at(&self, timestamp: i64) -> u64 {
+        if !self.is_utilization_curve_on() || timestamp <= self.utilization_updated_at {
+            return self.utilization_acc;
+        }
+        let elapsed = (timestamp - self.utilization_updated_at) as u64;
+        self.utilization_acc.saturating_add(self.utilization_bps() as u64 * elapsed)
+    }
+
+    /// Fold accrual at the current utilization into utilization_acc. Call before total_staked changes.
+    pub fn checkpoint_utilization(&mut self, current_time: i64) {
+        self.utilization_acc = self.utilization_acc_at(current_time);
+        self.utilization_updated_at = std::cmp::max(self.utilization_updated_at, current_time);
+    }
+
+    pub fn add_staked(&mut self, amount: u64, current_time: i64) {
+        self.checkpoint_utilization(current_time);
+        self.total_staked += amount;
+    }
+
+    pub fn remove_staked(&mut self, amount: u64, current_time: i64) {
+        self.checkpoint_utilization(current_time);
+        self.total_staked -= amount;
+    }
+
+    /// Take `amount` off a tier's locked principal. Every position is counted in its
+    /// tier, so a shortfall is an accounting error rather than something to clamp.
+    pub fn remove_tier_stake(&mut self, tier: usize, amount: u64) -> Result<()> {
+        self.tier_staked[tier] = self.tier_staked[tier]
+            .checked_sub(amount)
+            .ok_or(StakingError::TierAccountingMismatch)?;
+        Ok(())
+    }
+
+    /// Average utilization bps from `since` to `now`, given utilization_acc at `since`.
+    /// Time before the curve was turned on counts as 1x.
+    pub fn average_utilization_bps(&self, since: i64, acc_at_since: u64, now: i64) -> u32 {
+        if now <= since {
+            return self.utilization_bps();
+        }
+
+        let acc_now = self.utilization_acc_at(now) as u128;
+        let weighted = if since >= self.utilization_start {
+            acc_now.saturating_sub(acc_at_since as u128)
+        } else {
+            (self.utilization_start - since) as u128 * BPS_DENOMINATOR as u128 + acc_now
+        };
+        let average = (weighted / (now - since) as u128) as u32;
+        average.clamp(
+            std::cmp::min(self.utilization_min_bps, BPS_DENOMINATOR),
+            std::cmp::max(self.utilization_max_bps, BPS_DENOMINATOR),
+        )
+    }
+
+    /// Average loyalty bonus in bps over `start..end` for a user staking since `loyalty_since`.
+    /// The bonus grows linearly with staking time until it reaches the cap.
+    pub fn average_loyalty_bps(&self, loyalty_since: i64, start: i64, end: i64) -> u32 {
+        if self.loyalty_rate_bps == 0 || loyalty_since == 0 || end <= start {
+            return 0;
+        }
+
+        let (rate, cap) = (self.loyalty_rate_bps as u128, self.loyalty_cap_bps as u128);
+        let from = std::cmp::max(start - loyalty_since, 0) as u128;
+        let to = std::cmp::max(end - loyalty_since, 0) as u128;
+        let capped_at = cap * 86400 / rate; // Staking seconds until the cap is reached
+
+        // Linear part: rate * t / 1 day, integrated from a to b
+        let (a, b) = (std::cmp::min(from, capped_at), std::cmp::min(to, capped_at));
+        let growing = rate * (b * b - a * a) / (2 * 86400);
+        let capped = cap * (std::cmp::max(to, capped_at) - std::cmp::max(from, capped_at));
+
+        ((growing + capped) / (end - start) as u128) as u32
+    }
+
+    /// Emission rate (same units as reward_rate) in effect at `timestamp`
+    pub fn emission_rate_at(&self, timestamp: i64) -> u64 {
+        self.emission.rate_at(timestamp, self.reward_rate)
+    }
+
+    /// Emission rate integrated over `from..to`, in rate-seconds
+    pub fn emission_integral(&self, from: i64, to: i64) -> u128 {
+        self.emission.integral(from, to, self.reward_rate)
+    }
+
+    /// Scheduled, running and recently ended boost windows
+    pub fn extension_allowlist(&self) -> &[Pubkey] {
+        &self.extension_allowlist[..self.extension_allowlist_count as usize]
+    }
+
+    pub fn boost_windows(&self) -> &[BoostWindow] {
+        &self.boost_windows[..self.boost_window_count as usize]
+    }
+
+    pub fn push_boost_window(&mut self, window: BoostWindow) -> Result<()> {
+        let index = self.boost_window_count as usize;
+        require!(index < MAX_BOOST_WINDOWS, StakingError::TooManyBoostWindows);
+        self.boost_windows[index] = window;
+        self.boost_window_count += 1;
+        Ok(())
+    }
+
+    /// Remove a window, keeping the rest in order
+    pub fn remove_boost_window(&mut self, index: usize) {
+        let count = self.boost_window_count as usize;
+        self.boost_windows.copy_within(index + 1..count, index);
+        self.boost_windows[count - 1] = BoostWindow::zeroed();
+        self.boost_window_count -= 1;
+    }
+
+    /// Drop windows that ended longer ago than BOOST_WINDOW_RETENTION. Stakers who
+    /// have not been checkpointed since such a window lose its bonus.
+    pub fn prune_boost_windows(&mut self, current_time: i64) {
+        let mut index = 0;
+        while index < self.boost_window_count as usize {
+            if self.boost_windows[index].end + BOOST_WINDOW_RETENTION < current_time {
+                self.remove_boost_window(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+/// Reward rate over time, as a halving curve or a piecewise table
+#[zero_copy]
+#[derive(Debug)]
+pub struct EmissionSchedule {
+    pub start_time: i64,       // 0 = no schedule, the flat reward_rate applies
+    pub initial_rate: u64,     // Halving curve: rate at start_time
+    pub halving_interval: i64, // Halving curve: seconds between halvings
+    pub floor_rate: u64,       // Halving curve: rate never drops below this
+    pub segment_starts: [i64; MAX_EMISSION_SEGMENTS], // Table: ascending, the first is start_time
+    pub segment_rates: [u64; MAX_EMISSION_SEGMENTS],
+    pub segment_count: u8,     // 0 = use the halving curve
+    pub padding: [u8; 7],
+}
+
+impl EmissionSchedule {
+    pub fn is_set(&self) -> bool {
+        self.start_time != 0
+    }
+
+    fn segment_starts(&self) -> &[i64] {
+        &self.segment_starts[..self.segment_count as usize]
+    }
+
+    /// Rate at `timestamp`, or `flat_rate` before the schedule starts
+    pub fn rate_at(&self, timestamp: i64, flat_rate: u64) -> u64 {
+        if !self.is_set() || timestamp < self.start_time {
+            return flat_rate;
+        }
+
+        if self.segment_count > 0 {
+            let index = self.segment_starts().iter().rposition(|start| *start <= timestamp).unwrap_or(0);
+            return self.segment_rates[index];
+        }
+
+        let halvings = (timestamp - self.start_time) / self.halving_interval;
+        let halved = u32::try_from(halvings)
+            .ok()
+            .and_then(|halvings| self.initial_rate.checked_shr(halvings))
+            .unwrap_or(0);
+        std::cmp::max(halved, self.floor_rate)
+    }
+
+    /// First time after `timestamp` at which the rate changes, None once it is final
+    fn next_change(&self, timestamp: i64) -> Option<i64> {
+        if !self.is_set() {
+            return None;
+        }
+        if timestamp < self.start_time {
+            return Some(self.start_time);
+        }
+
+        if self.segment_count > 0 {
+            return self.segment_starts().iter().copied().find(|start| *start > timestamp);
+        }
+
+        // Every later halving would land below the floor
+        if self.rate_at(timestamp, 0) == self.floor_rate {
+            return None;
+        }
+        let halvings = (timestamp - self.start_time) / self.halving_interval;
+        (halvings + 1)
+            .checked_mul(self.halving_interval)
+            .and_then(|offset| self.start_time.checked_add(offset))
+    }
+
+    /// Rate integrated over `from..to`, in rate-seconds. Walks the rate changes in
+    /// between, at most one per table segment or halving above the floor.
+    pub fn integral(&self, from: i64, to: i64, flat_rate: u64) -> u128 {
+        let mut total = 0u128;
+        let mut cursor = from;
+        while cursor < to {
+            let next = self.next_change(cursor).map_or(to, |change| std::cmp::min(change, to));
+            total = total.saturating_add(self.rate_at(cursor, flat_rate) as u128 * (next - cursor) as u128);
+            cursor = next;
+        }
+        total
+    }
+}
+
+/// Reward multiplier applied to accrual between `start` and `end` for the lock tiers in `tiers`
+#[zero_copy]
+#[derive(Debug)]
+pub struct BoostWindow {
+    pub start: i64,
+    pub end: i64,
+    pub multiplier_bps: u32, // 10_000 = 1x
+    pub tiers: u8,           // Bit n set = applies to LockPeriod n
+    pub padding: [u8; 3],
+}
+
+/// Emergency unstake terms for one lock tier, copied onto each stake when it is locked
+/// so later changes only apply to new locks
+#[zero_copy]
+#[derive(Debug)]
+pub struct PenaltyConfig {
+    pub max_bps: u16,     // Penalty with the whole lock remaining
+    pub floor_bps: u16,   // Minimum penalty while the lock is running
+    pub burn_bps: u16,    // Share of the penalty burned
+    pub rewards_bps: u16, // Share of the penalty left for rewards, the rest goes to the treasury
+    pub curve: u8,        // PenaltyCurve discriminant
+    pub padding: [u8; 1],
+}
+
+#[account(zero_copy)]
+pub struct UserAccount {
+    pub authority: Pubkey,
+    pub delegate: Pubkey,        // Operator allowed to manage positions (default = none)
+    pub total_staked: u64,
+    pub pending_rewards: u64,
+    pub last_reward_time: i64,
+    pub last_crank_time: i64,
+    pub stakes: [StakeEntry; MAX_STAKES], // Only the first stake_count entries are in use
+    pub stake_count: u8,
+    pub bump: u8,
+    pub auto_compound: u8,        // Allow anyone to compound rewards on the user's behalf
+    pub delegate_permissions: u8, // Bitmask of DELEGATE_* permissions granted to the operator
+    pub version: u8,              // Layout version, see migrate_user
+    pub padding: [u8; 1],
+    pub boost_bps: u16,           // NFT holder reward bonus in basis points (0 = none)
+    pub boost_token_account: Pubkey, // Token account holding the boosting NFT
+    pub boost_mint: Pubkey,          // Mint of the boosting NFT
+    pub boost_collection: Pubkey,    // Verified collection the boost was granted for
+    pub utilization_snapshot: u64,   // Pool utilization_acc at last_reward_time
+    pub loyalty_since: i64,          // Start of uninterrupted stake (0 = nothing staked)
+    pub auto_renew: u16,             // Bit n set = stake n rolls into a new lock of its tier at expiry
+    pub reserved: [u8; 14],       // Padding for future fields without a realloc
+}
+
+impl UserAccount {
+    /// Stake entries created so far, including inactive ones
+    pub fn stakes(&self) -> &[StakeEntry] {
+        &self.stakes[..self.stake_count as usize]
+    }
+
+    pub fn stakes_mut(&mut self) -> &mut [StakeEntry] {
+        &mut self.stakes[..self.stake_count as usize]
+    }
+
+    /// Append a stake entry, failing once all MAX_STAKES slots are used
+    pub fn push_stake(&mut self, stake: StakeEntry) -> Result<()> {
+        let index = self.stake_count as usize;
+        require!(index < MAX_STAKES, StakingError::TooManyStakes);
+        self.stakes[index] = stake;
+        self.stake_count += 1;
+        Ok(())
+    }
+
+    pub fn is_auto_compound(&self) -> bool {
+        self.auto_compound != 0
+    }
+
+    /// Active auto-renew entries whose lock has ended and will roll over at the next checkpoint
+    pub fn due_rollovers(&self, current_time: i64) -> u64 {
+        self.stakes()
+            .iter()
+            .enumerate()
+            .filter(|(index, stake)| {
+                stake.is_active() && self.auto_renew & (1 << index) != 0 && current_time >= stake.lock_end
+            })
+            .count() as u64
+    }
+
+    /// Whether `signer` may perform an action needing `permission` on this account
+    pub fn can_act(&self, signer: &Pubkey, permission: u8) -> bool {
+        *signer == self.authority
+            || (self.delegate != Pubkey::default()
+                && *signer == self.delegate
+                && self.delegate_permissions & permission == permission)
+    }
+
+    pub fn is_boosted(&self) -> bool {
+        self.boost_bps != 0
+    }
+
+    /// Start the loyalty clock when stake goes up from zero and clear it when stake
+    /// returns to zero
+    pub fn track_loyalty(&mut self, current_time: i64) {
+        if self.total_staked == 0 {
+            self.loyalty_since = 0;
+        } else if self.loyalty_since == 0 {
+            self.loyalty_since = current_time;
+        }
+    }
+
+    /// Take away `decay_bps` of the uninterrupted staking time (10_000 = reset)
+    pub fn decay_loyalty(&mut self, decay_bps: u32, current_time: i64) {
+        if self.loyalty_since == 0 {
+            return;
+        }
+        let elapsed = std::cmp::max(current_time - self.loyalty_since, 0) as u128;
+        let kept = elapsed * (BPS_DENOMINATOR - decay_bps) as u128 / BPS_DENOMINATOR as u128;
+        self.loyalty_since = current_time - kept as i64;
+    }
+}
+
+/// Verified Metaplex collections whose holders earn boosted rewards
+#[account(zero_copy)]
+pub struct BoostConfig {
+    pub pool: Pubkey,
+    pub collections: [Pubkey; MAX_BOOST_COLLECTIONS], // Only the first count entries are in use
+    pub bonus_bps: [u16; MAX_BOOST_COLLECTIONS],      // Reward bonus per collection in basis points
+    pub count: u8,
+    pub bump: u8,
+    pub padding: [u8; 6],
+}
+
+impl BoostConfig {
+    /// Bonus for holders of `collection`, 0 if it is not boosted
+    pub fn bonus_for(&self, collection: &Pubkey) -> u16 {
+        self.collections[..self.count as usize]
+            .iter()
+            .position(|boosted| boosted == collection)
+            .map_or(0, |index| self.bonus_bps[index])
+    }
+
+    /// Add, update or (with a zero bonus) remove a collection
+    pub fn set_bonus(&mut self, collection: Pubkey, bonus_bps: u16) -> Result<()> {
+        let count = self.count as usize;
+        let index = self.collections[..count].iter().position(|boosted| *boosted == collection);
+
+        match (index, bonus_bps) {
+            (Some(index), 0) => {
+                // Move the last entry into the freed slot
+                self.collections[index] = self.collections[count - 1];
+                self.bonus_bps[index] = self.bonus_bps[count - 1];
+                self.collections[count - 1] = Pubkey::default();
+                self.bonus_bps[count - 1] = 0;
+                self.count -= 1;
+            }
+            (Some(index), _) => self.bonus_bps[index] = bonus_bps,
+            (None, 0) => return err!(StakingError::CollectionNotBoosted),
+            (None, _) => {
+                require!(count < MAX_BOOST_COLLECTIONS, StakingError::TooManyBoostCollections);
+                self.collections[count] = collection;
+                self.bonus_bps[count] = bonus_bps;
+                self.count += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tokens leaving a lock through the cooldown exit
+#[zero_copy]
+#[derive(Debug)]
+pub struct UnlockRequest {
+    pub amount: u64,
+    pub unlock_at: i64, // Withdrawable from this time
+}
+
+/// A user's pending cooldown exits, oldest first
+#[account(zero_copy)]
+pub struct UnlockQueue {
+    pub owner: Pubkey,
+    pub requests: [UnlockRequest; MAX_UNLOCK_REQUESTS], // Only the first count entries are in use
+    pub count: u8,
+    pub bump: u8,
+    pub padding: [u8; 6],
+}
+
+impl UnlockQueue {
+    /// Queue a request, failing once all MAX_UNLOCK_REQUESTS slots are used
+    pub fn push(&mut self, request: UnlockRequest) -> Result<()> {
+        let index = self.count as usize;
+        require!(index < MAX_UNLOCK_REQUESTS, StakingError::TooManyUnlockRequests);
+        self.requests[index] = request;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Remove the requests whose cooldown has passed and return their total
+    pub fn take_matured(&mut self, current_time: i64) -> u64 {
+        let count = self.count as usize;
+        let mut kept = 0;
+        let mut matured = 0;
+        for index in 0..count {
+            let request = self.requests[index];
+            if request.unlock_at <= current_time {
+                matured += request.amount;
+            } else {
+                self.requests[kept] = request;
+                kept += 1;
+            }
+        }
+        self.requests[kept..count].fill(UnlockRequest::zeroed());
+        self.count = kept as u8;
+        matured
+    }
+}
+
+#[zero_copy]
+#[derive(Debug)]
+pub struct StakeEntry {
+    pub amount: u64,
+    pub lock_start: i64,
+    pub lock_end: i64,
+    pub multiplier: u64, // Scaled by 1000 (1000 = 1.0x)
+    pub locked_bonus: u64, // Forfeitable lock bonus earned during the lock, paid out at lock_end
+    pub lock_period: u8, // LockPeriod discriminant
+    pub is_active: u8,
+    pub penalty: PenaltyConfig, // Emergency unstake terms locked in with the stake
+    pub padding: [u8; 4],
+}
+
+impl StakeEntry {
+    pub fn new(amount: u64, lock_period: LockPeriod, lock_start: i64, penalty: &PenaltyConfig) -> Self {
+        Self {
+            amount,
+            lock_start,
+            lock_end: lock_start + get_lock_duration(lock_period),
+            multiplier: get_lock_multiplier(lock_period),
+            locked_bonus: 0,
+            lock_period: lock_period as u8,
+            is_active: 1,
+            penalty: *penalty,
+            padding: [0; 4],
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_active != 0
+    }
+
+    pub fn lock_period(&self) -> Result<LockPeriod> {
+        LockPeriod::try_from(self.lock_period)
+    }
+
+    /// Emergency unstake penalty in basis points of principal at `current_time`
+    pub fn penalty_bps(&self, current_time: i64) -> Result<u64> {
+        Ok(PenaltyCurve::try_from(self.penalty.curve)?.penalty_bps(
+            self.penalty.max_bps,
+            self.penalty.floor_bps,
+            self.lock_end - current_time,
+            self.lock_end - self.lock_start,
+        ))
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockPeriod {
+    OneDay,
+    OneWeek,
+    ThreeMonths,
+    SixMonths,
+}
+
+impl TryFrom<u8> for LockPeriod {
+    type Error = anchor_lang::error::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(LockPeriod::OneDay),
+            1 => Ok(LockPeriod::OneWeek),
+            2 => Ok(LockPeriod::ThreeMonths),
+            3 => Ok(LockPeriod::SixMonths),
+            _ => err!(StakingError::InvalidLockPeriod),
+        }
+    }
+}
+
+/// How the emergency unstake penalty falls from max_bps to the floor over a lock
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PenaltyCurve {
+    Linear,    // Proportional to the time remaining
+    Quadratic, // Proportional to the square of the time remaining, forgiving late exits
+    Step,      // Time remaining rounded up to whole quarters of the lock
+}
+
+impl TryFrom<u8> for PenaltyCurve {
+    type Error = anchor_lang::error::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(PenaltyCurve::Linear),
+            1 => Ok(PenaltyCurve::Quadratic),
+            2 => Ok(PenaltyCurve::Step),
+            _ => err!(StakingError::InvalidPenaltyConfig),
+        }
+    }
+}
+
+impl PenaltyCurve {
+    /// Penalty with `remaining` of `duration` seconds left on the lock, never below the floor
+    pub fn penalty_bps(self, max_bps: u16, floor_bps: u16, remaining: i64, duration: i64) -> u64 {
+        let duration = duration.max(1) as u128;
+        let remaining = (remaining.max(0) as u128).min(duration);
+        let max = max_bps as u128;
+        let bps = match self {
+            PenaltyCurve::Linear => max * remaining / duration,
+            PenaltyCurve::Quadratic => max * remaining * remaining / (duration * duration),
+            PenaltyCurve::Step => max * (remaining * 4).div_ceil(duration) / 4,
+        };
+        bps.max(floor_bps as u128) as u64
+    }
+}
+
+/// One entry of a piecewise emission table
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub struct EmissionSegment {
+    pub start: i64,
+    pub rate: u64,
+}
+
+/// Arguments of `set_emission_schedule`. Pass either `segments`, whose first entry starts
+/// at `start_time`, or the halving curve fields.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct EmissionScheduleParams {
+    pub start_time: i64,
+    pub initial_rate: u64,
+    pub halving_interval: i64,
+    pub floor_rate: u64,
+    pub segments: Vec<EmissionSegment>,
+}
+
+impl EmissionScheduleParams {
+    fn to_schedule(&self) -> Result<EmissionSchedule> {
+        let mut schedule = EmissionSchedule::zeroed();
+        schedule.start_time = self.start_time;
+
+        if self.segments.is_empty() {
+            require!(
+                self.initial_rate > 0 && self.halving_interval > 0 && self.floor_rate <= self.initial_rate,
+                StakingError::InvalidEmissionSchedule
+            );
+            schedule.initial_rate = self.initial_rate;
+            schedule.halving_interval = self.halving_interval;
+            schedule.floor_rate = self.floor_rate;
+            return Ok(schedule);
+        }
+
+        require!(
+            self.segments.len() <= MAX_EMISSION_SEGMENTS
+                && self.segments[0].start == self.start_time
+                && self.segments.windows(2).all(|pair| pair[0].start < pair[1].start)
+                && self.initial_rate == 0
+                && self.halving_interval == 0
+                && self.floor_rate == 0,
+            StakingError::InvalidEmissionSchedule
+        );
+        for (index, segment) in self.segments.iter().enumerate() {
+            schedule.segment_starts[index] = segment.start;
+            schedule.segment_rates[index] = segment.rate;
+        }
+        schedule.segment_count = self.segments.len() as u8;
+        Ok(schedule)
+    }
+}
+
+/// Return value of the `pool_info` view
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PoolInfo {
+    pub total_staked: u64,
+    pub flexible_staked: u64,
+    pub reward_rate: u64,
+    pub paused: bool,
+    pub min_stakes: [u64; 4],
+    pub max_stake_per_user: u64,
+    pub max_total_staked: u64,
+    pub tier_staked: [u64; 4],
+    pub unmigrated_staked: u64,
+    pub tier_capacity: [u64; 4],
+    pub allowlist_root: [u8; 32],
+    pub emission_rate: u64, // Rate in effect now, see emission_rate_at
+    pub utilization_bps: u32, // Participation scaling of emission_rate, 10_000 = 1x
+}
+
+// Context structures
+#[derive(Accounts)]
+pub struct InitializePool<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = STAKING_POOL_SPACE,
+        seeds = [STAKING_POOL_SEED.as_bytes()],
+        bump
+    )]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(mint::token_program = token_program)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    #[account(mint::token_program = reward_token_program)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        init,
+        payer = authority,
+        seeds = [STAKING_VAULT_SEED.as_bytes(), staking_pool.key().as_ref()],
+        bump,
+        token::mint = staking_mint,
+        token::authority = staking_pool,
+        token::token_program = token_program,
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        init,
+        payer = authority,
+        seeds = [REWARD_VAULT_SEED.as_bytes(), staking_pool.key().as_ref()],
+        bump,
+        token::mint = reward_mint,
+        token::authority = staking_pool,
+        token::token_program = reward_token_program,
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub reward_token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeUser<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = USER_ACCOUNT_SPACE,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeReceiptMint<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        constraint = staking_mint.key() == staking_pool.load()?.staking_mint
+    )]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        seeds = [RECEIPT_MINT_SEED.as_bytes(), staking_pool.key().as_ref()],
+        bump,
+        mint::decimals = staking_mint.decimals,
+        mint::authority = staking_pool,
+        mint::token_program = token_program,
+    )]
+    pub receipt_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = USER_ACCOUNT_SPACE,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == staking_pool.load()?.staking_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == staking_pool.load()?.staking_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyUnstake<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == staking_pool.load()?.staking_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub treasury_account: InterfaceAccount<'info, TokenAccount>,
+    
+    /// Mutable for burning the penalty's burn share
+    #[account(mut, address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimAndUnstake<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == staking_pool.load()?.staking_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = user_reward_account.mint == staking_pool.load()?.reward_mint
+    )]
+    pub user_reward_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RequestUnlock<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = UNLOCK_QUEUE_SPACE,
+        seeds = [UNLOCK_QUEUE_SEED.as_bytes(), authority.key().as_ref()],
+        bump
+    )]
+    pub unlock_queue: AccountLoader<'info, UnlockQueue>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawUnlocked<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [UNLOCK_QUEUE_SEED.as_bytes(), authority.key().as_ref()],
+        bump = unlock_queue.load()?.bump
+    )]
+    pub unlock_queue: AccountLoader<'info, UnlockQueue>,
+    
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == staking_pool.load()?.staking_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_reward_account.mint == staking_pool.load()?.reward_mint
+    )]
+    pub user_reward_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewardsTo<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = destination.mint == staking_pool.load()?.reward_mint
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewardsFor<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), user_account.load()?.authority.as_ref()],
+        bump = user_account.load()?.bump,
+        constraint = user_account.load()?.can_act(&claimer.key(), DELEGATE_CLAIM) @ StakingError::Unauthorized
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub claimer: Signer<'info>,
+    
+    #[account(
+        mut,
+        address = get_associated_token_address_with_program_id(
+            &user_account.load()?.authority,
+            &staking_pool.load()?.reward_mint,
+            &token_program.key(),
+        )
+    )]
+    pub owner_reward_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct StakeFlexible<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == staking_pool.load()?.staking_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = user_receipt_account.mint == staking_pool.load()?.receipt_mint
+    )]
+    pub user_receipt_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = receipt_mint.key() == staking_pool.load()?.receipt_mint
+    )]
+    pub receipt_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct UnstakeFlexible<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == staking_pool.load()?.staking_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = user_receipt_account.mint == staking_pool.load()?.receipt_mint
+    )]
+    pub user_receipt_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = receipt_mint.key() == staking_pool.load()?.receipt_mint
+    )]
+    pub receipt_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Compound<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), user_account.load()?.authority.as_ref()],
+        bump = user_account.load()?.bump,
+        constraint = user_account.load()?.can_act(&operator.key(), DELEGATE_COMPOUND) @ StakingError::Unauthorized
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub operator: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetDelegate<'info> {
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExtendLock<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), user_account.load()?.authority.as_ref()],
+        bump = user_account.load()?.bump,
+        constraint = user_account.load()?.can_act(&operator.key(), DELEGATE_EXTEND_LOCK) @ StakingError::Unauthorized
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub operator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Restake<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), user_account.load()?.authority.as_ref()],
+        bump = user_account.load()?.bump,
+        constraint = user_account.load()?.can_act(&operator.key(), DELEGATE_RESTAKE) @ StakingError::Unauthorized
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub operator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAutoCompound<'info> {
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAutoRenew<'info> {
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AutoCompound<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), user_account.load()?.authority.as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    pub cranker: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Crank<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    pub cranker: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = cranker_reward_account.mint == staking_pool.load()?.reward_mint
+    )]
+    pub cranker_reward_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(address = staking_pool.load()?.reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetCrankConfig<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseUserAccount<'info> {
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
+        bump = user_account.load()?.bump,
+        close = authority
+    )]
+    pub user_account: AccountLoader<'info, UserAccount>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_reward_account.mint == staking_pool.load()?.reward_mint
+    )]
+    pub user_reward_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePool<'info> {
+    #[account(
+        mut,
+        close = authority
+    )]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
+    )]
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = reward_vault.key() == staking_pool.load()?.reward_vault
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    
+    /// Receives whatever is left in the staking vault
+    #[account(
+        mut,
+        constraint = staking_destination.mint == staking_pool.load()?.staking_mint
+    )]
+    pub staking_destination: InterfaceAccount<'info, TokenAccount>,
+    
+    /// Receives whatever is left in the reward vault
+    #[account(
+        mut,
+        constraint = reward_destination.mint == staking_pool.load()?.reward_mint
+    )]
+    pub reward_destination: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(address = staking_pool.load()?.reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub reward_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePool<'info> {
+    /// CHECK: Old layouts cannot be deserialized as the current StakingPool; owner,
+    /// discriminator and stored authority are validated in the handler
+    #[account(
+        mut,
+        seeds = [STAKING_POOL_SEED.as_bytes()],
+        bump
+    )]
+    pub staking_pool: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUser<'info> {
+    /// CHECK: Old layouts cannot be deserialized as the current UserAccount; owner,
+    /// discriminator and PDA address are validated in the handler
+    #[account(mut)]
+    pub user_account: UncheckedAccount<'info>,
+    
+    /// Must already be migrated
+    #[account(
+        mut,
+        seeds = [STAKING_POOL_SEED.as_bytes()],
+        bump = staking_pool.load()?.bump
+    )]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    #[account(mut)]
+    pub payer: Signer<'info>,
     
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Stake<'info> {
+pub struct SetLoyaltyConfig<'info> {
     #[account(mut)]
-    pub staking_pool: Account<'info, StakingPool>,
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
-    #[account(
-        mut,
-        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
-        bump = user_account.bump
-    )]
-    pub user_account: Account<'info, UserAccount>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPenaltyConfig<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawPenaltyRewards<'info> {
     #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
     pub authority: Signer<'info>,
     
     #[account(
         mut,
-        constraint = user_token_account.mint == staking_pool.staking_mint
+        constraint = staking_vault.key() == staking_pool.load()?.staking_vault
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub staking_vault: InterfaceAccount<'info, TokenAccount>,
     
     #[account(
         mut,
-        constraint = staking_vault.key() == staking_pool.staking_vault
+        constraint = destination.mint == staking_pool.load()?.staking_mint
     )]
-    pub staking_vault: Account<'info, TokenAccount>,
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(address = staking_pool.load()?.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
     
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
-pub struct Unstake<'info> {
+pub struct SetBonusForfeit<'info> {
     #[account(mut)]
-    pub staking_pool: Account<'info, StakingPool>,
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
-    #[account(
-        mut,
-        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
-        bump = user_account.bump
-    )]
-    pub user_account: Account<'info, UserAccount>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetUnlockCooldown<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetUtilizationCurve<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetEmissionSchedule<'info> {
     #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
     pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ScheduleBoostWindow<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeBoostConfig<'info> {
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+
     #[account(
-        mut,
-        constraint = user_token_account.mint == staking_pool.staking_mint
+        init,
+        payer = authority,
+        space = BOOST_CONFIG_SPACE,
+        seeds = [BOOST_CONFIG_SEED.as_bytes(), staking_pool.key().as_ref()],
+        bump
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    
+    pub boost_config: AccountLoader<'info, BoostConfig>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetBoostCollection<'info> {
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+
     #[account(
         mut,
-        constraint = staking_vault.key() == staking_pool.staking_vault
+        seeds = [BOOST_CONFIG_SEED.as_bytes(), staking_pool.key().as_ref()],
+        bump = boost_config.load()?.bump
     )]
-    pub staking_vault: Account<'info, TokenAccount>,
-    
-    pub token_program: Program<'info, Token>,
+    pub boost_config: AccountLoader<'info, BoostConfig>,
+
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct EmergencyUnstake<'info> {
-    #[account(mut)]
-    pub staking_pool: Account<'info, StakingPool>,
-    
+pub struct ApplyBoost<'info> {
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+
+    #[account(
+        seeds = [BOOST_CONFIG_SEED.as_bytes(), staking_pool.key().as_ref()],
+        bump = boost_config.load()?.bump
+    )]
+    pub boost_config: AccountLoader<'info, BoostConfig>,
+
     #[account(
         mut,
         seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
-        bump = user_account.bump
+        bump = user_account.load()?.bump
     )]
-    pub user_account: Account<'info, UserAccount>,
-    
-    #[account(mut)]
+    pub user_account: AccountLoader<'info, UserAccount>,
+
     pub authority: Signer<'info>,
-    
+
+    #[account(mint::decimals = 0)]
+    pub nft_mint: InterfaceAccount<'info, Mint>,
+
     #[account(
-        mut,
-        constraint = user_token_account.mint == staking_pool.staking_mint
+        token::mint = nft_mint,
+        token::authority = authority,
+        constraint = nft_token_account.amount == 1 @ StakingError::NotAnNft
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    
+    pub nft_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Metaplex metadata PDA of the NFT mint, parsed in apply_boost
     #[account(
-        mut,
-        constraint = staking_vault.key() == staking_pool.staking_vault
+        seeds = [boost::METADATA_SEED, boost::TOKEN_METADATA_PROGRAM_ID.as_ref(), nft_mint.key().as_ref()],
+        bump,
+        seeds::program = boost::TOKEN_METADATA_PROGRAM_ID
     )]
-    pub staking_vault: Account<'info, TokenAccount>,
-    
-    #[account(mut)]
-    pub treasury_account: Account<'info, TokenAccount>,
-    
-    pub token_program: Program<'info, Token>,
+    pub nft_metadata: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
-pub struct ClaimRewards<'info> {
-    #[account(mut)]
-    pub staking_pool: Account<'info, StakingPool>,
-    
+pub struct RefreshBoost<'info> {
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+
     #[account(
-        mut,
-        seeds = [USER_ACCOUNT_SEED.as_bytes(), authority.key().as_ref()],
-        bump = user_account.bump
+        seeds = [BOOST_CONFIG_SEED.as_bytes(), staking_pool.key().as_ref()],
+        bump = boost_config.load()?.bump
     )]
-    pub user_account: Account<'info, UserAccount>,
-    
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    
+    pub boost_config: AccountLoader<'info, BoostConfig>,
+
     #[account(
         mut,
-        constraint = user_reward_account.mint == staking_pool.reward_mint
+        seeds = [USER_ACCOUNT_SEED.as_bytes(), user_account.load()?.authority.as_ref()],
+        bump = user_account.load()?.bump
     )]
-    pub user_reward_account: Account<'info, TokenAccount>,
+    pub user_account: AccountLoader<'info, UserAccount>,
+
+    /// CHECK: The token account recorded when the boost was applied. It may have been
+    /// closed or emptied since, which refresh_boost checks.
+    #[account(address = user_account.load()?.boost_token_account)]
+    pub nft_token_account: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetStakeCaps<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
-    #[account(
-        mut,
-        constraint = reward_vault.key() == staking_pool.reward_vault
-    )]
-    pub reward_vault: Account<'info, TokenAccount>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAllowlistRoot<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
+    
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetExtensionAllowlist<'info> {
+    #[account(mut)]
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
-    pub token_program: Program<'info, Token>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PoolInfoView<'info> {
+    pub staking_pool: AccountLoader<'info, StakingPool>,
 }
 
 #[derive(Accounts)]
 pub struct SetPaused<'info> {
     #[account(mut)]
-    pub staking_pool: Account<'info, StakingPool>,
+    pub staking_pool: AccountLoader<'info, StakingPool>,
     
     pub authority: Signer<'info>,
 }
@@ -639,12 +4261,95 @@ pub struct UnstakeEvent {
     pub penalty: u64,
 }
 
+/// One entry withdrawn by a batch unstake
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct UnstakedEntry {
+    pub stake_index: u8,
+    pub amount: u64,
+}
+
+#[event]
+pub struct BatchUnstakeEvent {
+    pub user: Pubkey,
+    pub amount: u64,          // Total principal returned
+    pub rewards_claimed: u64, // 0 for unstake_all_expired
+    pub entries: Vec<UnstakedEntry>,
+}
+
 #[event]
 pub struct ClaimRewardsEvent {
     pub user: Pubkey,
     pub amount: u64,
 }
 
+#[event]
+pub struct RelockEvent {
+    pub user: Pubkey,
+    pub stake_index: u8,
+    pub lock_period: LockPeriod,
+    pub lock_end: i64,
+    pub multiplier: u64,
+}
+
+#[event]
+pub struct DelegateSetEvent {
+    pub user: Pubkey,
+    pub delegate: Pubkey,
+    pub permissions: u8,
+}
+
+#[event]
+pub struct CompoundEvent {
+    pub user: Pubkey,
+    pub stake_index: u8,
+    pub amount: u64,
+}
+
+#[event]
+pub struct CrankEvent {
+    pub cranker: Pubkey,
+    pub users_serviced: u64,
+    pub entries_serviced: u64,
+    pub total_compounded: u64,
+    pub bounty: u64,
+}
+
+#[event]
+pub struct FlexibleStakeEvent {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub receipt_amount: u64,
+}
+
+#[event]
+pub struct FlexibleUnstakeEvent {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub receipt_amount: u64,
+}
+
+#[event]
+pub struct BoostEvent {
+    pub user: Pubkey,
+    pub collection: Pubkey,
+    pub bonus_bps: u16, // 0 when the boost was removed
+}
+
+#[event]
+pub struct UnlockRequestedEvent {
+    pub user: Pubkey,
+    pub stake_index: u8,
+    pub amount: u64,
+    pub unlock_at: i64,
+}
+
+#[event]
+pub struct RewardsForfeitedEvent {
+    pub user: Pubkey,
+    pub stake_index: u8,
+    pub amount: u64,
+}
+
 // Error definitions
 #[error_code]
 pub enum StakingError {
@@ -720,4 +4425,307 @@ pub enum StakingError {
     
     #[msg("Stake amount too small")]
     StakeAmountTooSmall,
-}
\ No newline at end of file
+
+    #[msg("Flexible staking is not enabled for this pool")]
+    FlexibleStakingDisabled,
+
+    #[msg("Compounding requires the reward mint to match the staking mint")]
+    CompoundMintMismatch,
+
+    #[msg("Pending rewards below minimum compound amount")]
+    BelowMinimumCompound,
+
+    #[msg("Auto-compound is not enabled for this user")]
+    AutoCompoundDisabled,
+
+    #[msg("No locked stake available to compound into")]
+    NoCompoundableStake,
+
+    #[msg("Crank batch must contain between 1 and 10 user accounts")]
+    InvalidCrankBatch,
+
+    #[msg("Invalid user account passed to crank")]
+    InvalidCrankAccount,
+
+    #[msg("Invalid crank configuration")]
+    InvalidCrankConfig,
+
+    #[msg("Invalid delegate permissions")]
+    InvalidDelegatePermissions,
+
+    #[msg("New lock must be at least as long as the current one")]
+    InvalidLockExtension,
+
+    #[msg("Mint uses an unsupported Token-2022 extension")]
+    UnsupportedMintExtension,
+
+    #[msg("Mint decimals too large for minimum stake amounts")]
+    InvalidMintDecimals,
+
+    #[msg("User still has active stakes")]
+    HasActiveStakes,
+
+    #[msg("Pool still holds staked tokens or vault balances")]
+    PoolNotEmpty,
+
+    #[msg("Account is already on the current layout version")]
+    AlreadyMigrated,
+
+    #[msg("Account data does not match a known layout")]
+    InvalidAccountLayout,
+
+    #[msg("Stake would exceed the per-user staking cap")]
+    UserCapExceeded,
+
+    #[msg("Stake would exceed the pool's total staking cap")]
+    PoolCapExceeded,
+
+    #[msg("Lock tier is at capacity")]
+    TierCapacityExceeded,
+
+    #[msg("Pool is allowlisted, stake with a Merkle proof")]
+    AllowlistProofRequired,
+
+    #[msg("Pool has no allowlist")]
+    AllowlistNotEnabled,
+
+    #[msg("Merkle proof does not match the allowlist")]
+    InvalidAllowlistProof,
+
+    #[msg("Stake would exceed this address's allowlist cap")]
+    AllowlistCapExceeded,
+
+    #[msg("Boost bonus exceeds the maximum")]
+    InvalidBoost,
+
+    #[msg("Too many boosted collections")]
+    TooManyBoostCollections,
+
+    #[msg("Collection is not boosted")]
+    CollectionNotBoosted,
+
+    #[msg("Token is not a single-supply NFT held by the signer")]
+    NotAnNft,
+
+    #[msg("Metadata is not a Metaplex account with a verified collection")]
+    InvalidNftMetadata,
+
+    #[msg("User has no active boost")]
+    NoActiveBoost,
+
+    #[msg("Boost window must start in the future with a multiplier above 1x for at least one tier")]
+    InvalidBoostWindow,
+
+    #[msg("Too many boost windows scheduled")]
+    TooManyBoostWindows,
+
+    #[msg("Boost window has already started")]
+    BoostWindowStarted,
+
+    #[msg("Emission schedule must start in the future with either a halving curve or an ascending table")]
+    InvalidEmissionSchedule,
+
+    #[msg("Emission schedule has already started")]
+    EmissionScheduleStarted,
+
+    #[msg("Utilization curve needs min <= 1x <= max within bounds")]
+    InvalidUtilizationCurve,
+
+    #[msg("Loyalty cap or decay out of range")]
+    InvalidLoyaltyConfig,
+
+    #[msg("Penalty needs floor <= max within bounds and a split of at most 100%")]
+    InvalidPenaltyConfig,
+
+    #[msg("Bonus forfeit share above 100%")]
+    InvalidBonusForfeit,
+
+    #[msg("Cooldown exits are disabled")]
+    UnlockCooldownDisabled,
+
+    #[msg("Too many unlock requests waiting")]
+    TooManyUnlockRequests,
+
+    #[msg("No unlock request has finished its cooldown")]
+    NothingToWithdraw,
+
+    #[msg("Unlock cooldown longer than the longest lock")]
+    InvalidUnlockCooldown,
+
+    #[msg("No expired stakes to unstake")]
+    NoExpiredStakes,
+
+    #[msg("Flexible staking requires the reward mint to match the staking mint")]
+    FlexibleMintMismatch,
+
+    #[msg("Tier capacities need every staked user account migrated first")]
+    TierBackfillPending,
+
+    #[msg("Per-tier staked totals do not cover the position")]
+    TierAccountingMismatch,
+
+    #[msg("No expired stakes to unstake and no rewards to claim")]
+    NothingToClaimOrUnstake,
+
+    #[msg("Too many mints in the extension allowlist")]
+    ExtensionAllowlistFull,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86400;
+
+    fn schedule(params: EmissionScheduleParams) -> EmissionSchedule {
+        params.to_schedule().unwrap()
+    }
+
+    /// Integral by summing the rate one second at a time
+    fn summed_rate(schedule: &EmissionSchedule, from: i64, to: i64, flat_rate: u64) -> u128 {
+        (from..to).map(|t| schedule.rate_at(t, flat_rate) as u128).sum()
+    }
+
+    #[test]
+    fn emission_integral_without_a_schedule_is_flat() {
+        let mut pool = StakingPool::zeroed();
+        pool.reward_rate = 2_500_000;
+        assert_eq!(pool.emission_integral(100, 160), 2_500_000 * 60);
+        assert_eq!(pool.emission_integral(160, 100), 0);
+    }
+
+    #[test]
+    fn emission_integral_follows_the_halving_curve() {
+        let halving = schedule(EmissionScheduleParams {
+            start_time: 1_000,
+            initial_rate: 800,
+            halving_interval: 100,
+            floor_rate: 100,
+            segments: vec![],
+        });
+
+        // Flat 50 until the start, then 800, 400, 200 and the 100 floor from 1300 on
+        assert_eq!(halving.integral(900, 1_450, 50), 5_000 + 80_000 + 40_000 + 20_000 + 15_000);
+        assert_eq!(halving.rate_at(1_000_000, 50), 100);
+        for (from, to) in [(0, 2_000), (1_050, 1_250), (1_399, 1_401), (1_100, 1_100)] {
+            assert_eq!(halving.integral(from, to, 50), summed_rate(&halving, from, to, 50));
+        }
+    }
+
+    #[test]
+    fn emission_integral_follows_the_table() {
+        let table = schedule(EmissionScheduleParams {
+            start_time: 1_000,
+            initial_rate: 0,
+            halving_interval: 0,
+            floor_rate: 0,
+            segments: vec![
+                EmissionSegment { start: 1_000, rate: 10 },
+                EmissionSegment { start: 1_050, rate: 30 },
+                EmissionSegment { start: 1_200, rate: 5 },
+            ],
+        });
+
+        assert_eq!(table.integral(990, 1_210, 7), 70 + 500 + 4_500 + 50);
+        for (from, to) in [(0, 1_500), (1_049, 1_051), (1_300, 1_400)] {
+            assert_eq!(table.integral(from, to, 7), summed_rate(&table, from, to, 7));
+        }
+    }
+
+    #[test]
+    fn average_loyalty_grows_linearly_up_to_the_cap() {
+        let mut pool = StakingPool::zeroed();
+        pool.loyalty_rate_bps = 10; // +0.1% per day, capped after 250 days
+        pool.loyalty_cap_bps = 2_500;
+        let since = 1_000_000;
+
+        // Growing from 0 to 100 bps
+        assert_eq!(pool.average_loyalty_bps(since, since, since + 10 * DAY), 50);
+        // Half growing, half at the cap
+        assert_eq!(pool.average_loyalty_bps(since, since + 240 * DAY, since + 260 * DAY), 2_475);
+        assert_eq!(pool.average_loyalty_bps(since, since + 300 * DAY, since + 310 * DAY), 2_500);
+        // Time before the user started staking earns nothing
+        assert_eq!(pool.average_loyalty_bps(since, since - 10 * DAY, since + 10 * DAY), 25);
+    }
+
+    #[test]
+    fn average_loyalty_is_zero_when_off_or_empty() {
+        let mut pool = StakingPool::zeroed();
+        assert_eq!(pool.average_loyalty_bps(1_000, 2_000, 2_000 + DAY), 0);
+
+        pool.loyalty_rate_bps = 10;
+        pool.loyalty_cap_bps = 2_500;
+        assert_eq!(pool.average_loyalty_bps(0, 2_000, 2_000 + DAY), 0);
+        assert_eq!(pool.average_loyalty_bps(1_000, 2_000, 2_000), 0);
+        assert_eq!(pool.average_loyalty_bps(1_000, 2_000, 1_500), 0);
+    }
+    #[test]
+    fn penalty_curves_fall_from_max_over_the_lock() {
+        let duration = 100;
+        let at = |curve: PenaltyCurve, remaining: i64| curve.penalty_bps(3_300, 0, remaining, duration);
+
+        assert_eq!(at(PenaltyCurve::Linear, 100), 3_300);
+        assert_eq!(at(PenaltyCurve::Linear, 50), 1_650);
+        assert_eq!(at(PenaltyCurve::Linear, 0), 0);
+
+        assert_eq!(at(PenaltyCurve::Quadratic, 100), 3_300);
+        assert_eq!(at(PenaltyCurve::Quadratic, 50), 825);
+        assert_eq!(at(PenaltyCurve::Quadratic, 10), 33);
+
+        // Time remaining rounds up to whole quarters
+        assert_eq!(at(PenaltyCurve::Step, 100), 3_300);
+        assert_eq!(at(PenaltyCurve::Step, 51), 2_475);
+        assert_eq!(at(PenaltyCurve::Step, 26), 1_650);
+        assert_eq!(at(PenaltyCurve::Step, 1), 825);
+        assert_eq!(at(PenaltyCurve::Step, 0), 0);
+    }
+
+    #[test]
+    fn penalty_curves_clamp_time_and_keep_the_floor() {
+        for curve in [PenaltyCurve::Linear, PenaltyCurve::Quadratic, PenaltyCurve::Step] {
+            // Remaining time outside the lock is clamped to it
+            assert_eq!(curve.penalty_bps(3_300, 0, 500, 100), 3_300);
+            assert_eq!(curve.penalty_bps(3_300, 0, -5, 100), 0);
+
+            assert_eq!(curve.penalty_bps(3_300, 500, 0, 100), 500);
+            assert_eq!(curve.penalty_bps(3_300, 500, 100, 100), 3_300);
+            assert_eq!(curve.penalty_bps(2_000, 2_000, 37, 100), 2_000);
+
+            // A zero-length lock does not divide by zero
+            assert_eq!(curve.penalty_bps(3_300, 0, 0, 0), 0);
+        }
+        assert_eq!(PenaltyCurve::Quadratic.penalty_bps(3_300, 500, 30, 100), 500);
+    }
+
+    #[test]
+    fn rollover_pays_out_the_bonus_of_served_locks() {
+        let mut pool = StakingPool::zeroed();
+        pool.reward_rate = 1_000_000_000;
+        pool.bonus_forfeit_bps = BPS_DENOMINATOR;
+
+        let start = 1_000_000;
+        let amount = 1_000_000_000_000;
+        let mut user_account = UserAccount::zeroed();
+        user_account.last_reward_time = start;
+        user_account.auto_renew = 1;
+        user_account
+            .push_stake(StakeEntry::new(amount, LockPeriod::OneDay, start, &DEFAULT_PENALTY))
+            .unwrap();
+        let multiplier = user_account.stakes()[0].multiplier;
+        let day_reward = accrued_reward(amount, pool.emission_integral(start, start + DAY), multiplier).unwrap();
+        let day_base = accrued_reward(amount, pool.emission_integral(start, start + DAY), BASE_MULTIPLIER).unwrap();
+
+        // A checkpoint a day and a half in rolls the entry over. The served day pays in
+        // full; only the half day of the renewed lock is held back.
+        update_user_rewards(&mut user_account, &pool, start + DAY + DAY / 2).unwrap();
+        let stake = user_account.stakes()[0];
+        assert_eq!(stake.lock_start, start + DAY);
+        assert_eq!(stake.locked_bonus, (day_reward - day_base) / 2);
+        assert_eq!(user_account.pending_rewards, day_reward + day_base / 2);
+
+        // Leaving early forfeits the half day's bonus and nothing from the served lock
+        forfeit_lock_bonus(&mut user_account, 0, amount).unwrap();
+        assert_eq!(user_account.stakes()[0].locked_bonus, 0);
+        assert_eq!(user_account.pending_rewards, day_reward + day_base / 2);
+    }
+}
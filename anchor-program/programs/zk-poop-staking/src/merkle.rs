@@ -0,0 +1,161 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hashv;
+
+/// Longest proof accepted on-chain, enough for an allowlist of 2^16 addresses
+pub const MAX_PROOF_DEPTH: usize = 16;
+
+/// Prefixes that keep a leaf from being passed off as an inner node
+const LEAF_PREFIX: &[u8] = &[0];
+const NODE_PREFIX: &[u8] = &[1];
+
+/// Hash an allowlist entry: the staker's key and their cap in base units (0 = uncapped)
+pub fn leaf_hash(staker: &Pubkey, max_amount: u64) -> [u8; 32] {
+    hashv(&[LEAF_PREFIX, staker.as_ref(), &max_amount.to_le_bytes()]).to_bytes()
+}
+
+/// Hash two sibling nodes. Pairs are sorted so proofs need no left/right flags.
+pub fn hash_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    hashv(&[NODE_PREFIX, left, right]).to_bytes()
+}
+
+/// Check that `leaf` is in the tree with the given root
+pub fn verify_proof(proof: &[[u8; 32]], root: &[u8; 32], leaf: [u8; 32]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| hash_pair(&node, sibling));
+    computed == *root
+}
+
+/// Allowlist tree builder for tests and ops tooling. Not compiled into the on-chain program.
+#[cfg(not(target_os = "solana"))]
+pub struct MerkleTree {
+    /// Levels from the leaves up to the root. An odd node is carried up unpaired.
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+#[cfg(not(target_os = "solana"))]
+impl MerkleTree {
+    /// Build a tree over `(staker, max_amount)` entries, in the order given
+    pub fn new(entries: &[(Pubkey, u64)]) -> Self {
+        let leaves: Vec<[u8; 32]> = entries
+            .iter()
+            .map(|(staker, max_amount)| leaf_hash(staker, *max_amount))
+            .collect();
+
+        let mut levels = vec![leaves];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => hash_pair(a, b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        MerkleTree { levels }
+    }
+
+    /// Root to store with `set_allowlist_root`. An empty tree has the all-zero root,
+    /// which leaves the pool open.
+    pub fn root(&self) -> [u8; 32] {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Proof for the entry at `index`, or None if out of range
+    pub fn proof(&self, index: usize) -> Option<Vec<[u8; 32]>> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        let mut index = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        Some(proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(count: u8) -> Vec<(Pubkey, u64)> {
+        (0..count)
+            .map(|i| (Pubkey::new_from_array([i + 1; 32]), i as u64 * 1_000))
+            .collect()
+    }
+
+    #[test]
+    fn every_entry_proves_against_the_root() {
+        // Odd sizes carry a node up unpaired
+        for count in 1..=9 {
+            let entries = entries(count);
+            let tree = MerkleTree::new(&entries);
+            for (index, (staker, max_amount)) in entries.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(proof.len() <= MAX_PROOF_DEPTH);
+                assert!(verify_proof(&proof, &tree.root(), leaf_hash(staker, *max_amount)));
+            }
+            assert!(tree.proof(entries.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_a_leaf_not_in_the_tree() {
+        let entries = entries(4);
+        let tree = MerkleTree::new(&entries);
+        let proof = tree.proof(0).unwrap();
+        let stranger = Pubkey::new_from_array([42; 32]);
+        assert!(!verify_proof(&proof, &tree.root(), leaf_hash(&stranger, entries[0].1)));
+        // A real leaf with another entry's proof
+        assert!(!verify_proof(&proof, &tree.root(), leaf_hash(&entries[1].0, entries[1].1)));
+    }
+
+    #[test]
+    fn rejects_a_different_cap() {
+        let entries = entries(4);
+        let tree = MerkleTree::new(&entries);
+        let (staker, max_amount) = entries[2];
+        let proof = tree.proof(2).unwrap();
+        assert!(!verify_proof(&proof, &tree.root(), leaf_hash(&staker, max_amount + 1)));
+        assert!(!verify_proof(&proof, &tree.root(), leaf_hash(&staker, 0)));
+    }
+
+    #[test]
+    fn empty_proof_only_matches_a_single_leaf_root() {
+        let single = entries(1);
+        let tree = MerkleTree::new(&single);
+        let leaf = leaf_hash(&single[0].0, single[0].1);
+        assert_eq!(tree.root(), leaf);
+        assert!(tree.proof(0).unwrap().is_empty());
+        assert!(verify_proof(&[], &tree.root(), leaf));
+
+        let tree = MerkleTree::new(&entries(4));
+        assert!(!verify_proof(&[], &tree.root(), leaf));
+        assert_eq!(MerkleTree::new(&[]).root(), [0; 32]);
+    }
+
+    #[test]
+    fn hashes_are_domain_separated_and_pairs_sorted() {
+        let entries = entries(2);
+        let a = leaf_hash(&entries[0].0, entries[0].1);
+        let b = leaf_hash(&entries[1].0, entries[1].1);
+        assert_eq!(hash_pair(&a, &b), hash_pair(&b, &a));
+
+        // Neither hash matches the same bytes hashed without its prefix
+        let unprefixed_leaf = hashv(&[entries[0].0.as_ref(), &entries[0].1.to_le_bytes()]).to_bytes();
+        assert_ne!(a, unprefixed_leaf);
+        let (left, right) = if a <= b { (a, b) } else { (b, a) };
+        assert_ne!(hash_pair(&a, &b), hashv(&[&left, &right]).to_bytes());
+    }
+}
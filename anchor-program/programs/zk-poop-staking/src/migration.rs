@@ -0,0 +1,204 @@
+use anchor_lang::prelude::*;
+use anchor_lang::system_program;
+use bytemuck::Zeroable;
+use crate::{
+    BoostWindow, EmissionSchedule, LockPeriod, StakeEntry, StakingError, StakingPool, UserAccount,
+    DEFAULT_PENALTY, MAX_BOOST_WINDOWS, MAX_EXTENSION_ALLOWLIST, MAX_STAKES, MIN_CRANK_INTERVAL, STAKING_POOL_VERSION, USER_ACCOUNT_VERSION,
+};
+
+/// Size of the original, unversioned pool layout
+pub const STAKING_POOL_V0_SPACE: usize = 8 + 32 + 32 + 32 + 32 + 32 + 8 + 8 + 1 + 1;
+
+/// Size of the original, unversioned user layout
+pub const USER_ACCOUNT_V0_SPACE: usize = 8 + 32 + 8 + 8 + 8 + (4 + 10 * (8 + 1 + 8 + 8 + 8 + 1)) + 1;
+
+/// Original pool layout, before accounts carried a version byte
+#[derive(AnchorDeserialize)]
+pub struct StakingPoolV0 {
+    pub authority: Pubkey,
+    pub staking_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub staking_vault: Pubkey,
+    pub reward_vault: Pubkey,
+    pub total_staked: u64,
+    pub reward_rate: u64,
+    pub bump: u8,
+    pub paused: bool,
+}
+
+impl StakingPoolV0 {
+    /// Upgrade to the current zero-copy layout, filling new fields with their defaults.
+    /// Per-tier totals are backfilled as user accounts migrate, so all existing stake
+    /// starts out unmigrated. Penalty configs start at the original 33% linear curve
+    /// with a 40/40/20 split.
+    pub fn upgrade(self, staking_decimals: u8, min_stakes: [u64; 4]) -> StakingPool {
+        StakingPool {
+            authority: self.authority,
+            staking_mint: self.staking_mint,
+            reward_mint: self.reward_mint,
+            staking_vault: self.staking_vault,
+            reward_vault: self.reward_vault,
+            receipt_mint: Pubkey::default(),
+            total_staked: self.total_staked,
+            reward_rate: self.reward_rate,
+            flexible_staked: 0,
+            flexible_last_update: 0,
+            crank_bounty: 0,
+            crank_interval: MIN_CRANK_INTERVAL,
+            crank_bounty_cap: 0,
+            crank_bounty_budget: 0,
+            min_stakes,
+            bump: self.bump,
+            paused: self.paused as u8,
+            staking_decimals,
+            version: STAKING_POOL_VERSION,
+            staking_vault_bump: 0,
+            reward_vault_bump: 0,
+            receipt_mint_bump: 0,
+            padding: [0; 1],
+            max_stake_per_user: 0,
+            max_total_staked: 0,
+            tier_capacity: [0; 4],
+            tier_staked: [0; 4],
+            unmigrated_staked: self.total_staked,
+            penalty_rewards: 0,
+            unlocking: 0,
+            allowlist_root: [0; 32],
+            extension_allowlist: [Pubkey::default(); MAX_EXTENSION_ALLOWLIST],
+            boost_windows: [BoostWindow::zeroed(); MAX_BOOST_WINDOWS],
+            boost_window_count: 0,
+            extension_allowlist_count: 0,
+            padding2: [0; 6],
+            emission: EmissionSchedule::zeroed(),
+            utilization_target: 0,
+            utilization_acc: 0,
+            utilization_start: 0,
+            utilization_updated_at: 0,
+            flexible_utilization_snapshot: 0,
+            utilization_min_bps: 0,
+            utilization_max_bps: 0,
+            loyalty_rate_bps: 0,
+            loyalty_cap_bps: 0,
+            loyalty_decay_bps: 0,
+            padding3: [0; 4],
+            penalty_configs: [DEFAULT_PENALTY; 4],
+            bonus_forfeit_bps: 0,
+            unlock_cooldown: 0,
+            reserved: [0; 8],
+        }
+    }
+}
+
+/// Borsh stake entry used by the original user layout
+#[derive(AnchorDeserialize)]
+pub struct StakeEntryV0 {
+    pub amount: u64,
+    pub lock_period: LockPeriod,
+    pub lock_start: i64,
+    pub lock_end: i64,
+    pub multiplier: u64,
+    pub is_active: bool,
+}
+
+/// Original user layout, before accounts carried a version byte
+#[derive(AnchorDeserialize)]
+pub struct UserAccountV0 {
+    pub authority: Pubkey,
+    pub total_staked: u64,
+    pub pending_rewards: u64,
+    pub last_reward_time: i64,
+    pub stakes: Vec<StakeEntryV0>,
+    pub bump: u8,
+}
+
+impl UserAccountV0 {
+    /// Upgrade to the current zero-copy layout, moving stakes into the fixed array.
+    /// Stakes keep the original 33% linear penalty with a 40/40/20 split.
+    pub fn upgrade(self) -> Result<UserAccount> {
+        require!(self.stakes.len() <= MAX_STAKES, StakingError::InvalidAccountLayout);
+
+        let mut stakes = [StakeEntry::zeroed(); MAX_STAKES];
+        for (slot, stake) in stakes.iter_mut().zip(&self.stakes) {
+            *slot = StakeEntry {
+                amount: stake.amount,
+                lock_start: stake.lock_start,
+                lock_end: stake.lock_end,
+                multiplier: stake.multiplier,
+                locked_bonus: 0,
+                lock_period: stake.lock_period as u8,
+                is_active: stake.is_active as u8,
+                penalty: DEFAULT_PENALTY,
+                padding: [0; 4],
+            };
+        }
+
+        Ok(UserAccount {
+            authority: self.authority,
+            delegate: Pubkey::default(),
+            total_staked: self.total_staked,
+            pending_rewards: self.pending_rewards,
+            last_reward_time: self.last_reward_time,
+            last_crank_time: 0,
+            stakes,
+            stake_count: self.stakes.len() as u8,
+            bump: self.bump,
+            auto_compound: 0,
+            delegate_permissions: 0,
+            version: USER_ACCOUNT_VERSION,
+            padding: [0; 1],
+            boost_bps: 0,
+            boost_token_account: Pubkey::default(),
+            boost_mint: Pubkey::default(),
+            boost_collection: Pubkey::default(),
+            utilization_snapshot: 0,
+            loyalty_since: 0,
+            auto_renew: 0,
+            reserved: [0; 14],
+        })
+    }
+}
+
+/// Check that an account is owned by this program and carries the expected discriminator
+pub fn validate_discriminator(account: &AccountInfo, program_id: &Pubkey, discriminator: &[u8]) -> Result<()> {
+    require!(account.owner == program_id, StakingError::InvalidAccountLayout);
+    let data = account.try_borrow_data()?;
+    require!(
+        data.len() >= discriminator.len() && &data[..discriminator.len()] == discriminator,
+        StakingError::InvalidAccountLayout
+    );
+    Ok(())
+}
+
+/// Grow an account to `new_len`, topping up rent exemption from `payer`
+pub fn resize_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<()> {
+    let required_lamports = Rent::get()?.minimum_balance(new_len);
+    let top_up = required_lamports.saturating_sub(account.lamports());
+
+    if top_up > 0 {
+        let cpi_ctx = CpiContext::new(
+            system_program.clone(),
+            system_program::Transfer {
+                from: payer.clone(),
+                to: account.clone(),
+            },
+        );
+        system_program::transfer(cpi_ctx, top_up)?;
+    }
+
+    account.resize(new_len)?;
+    Ok(())
+}
+
+/// Write a zero-copy account body after its (unchanged) discriminator
+pub fn write_zero_copy<T: bytemuck::Pod>(account: &AccountInfo, value: &T) -> Result<()> {
+    let mut data = account.try_borrow_mut_data()?;
+    let body = bytemuck::bytes_of(value);
+    require!(data.len() == 8 + body.len(), StakingError::InvalidAccountLayout);
+    data[8..].copy_from_slice(body);
+    Ok(())
+}
@@ -1,5 +1,16 @@
 use anchor_lang::prelude::*;
-use crate::{StakingError, UserAccount, StakeEntry, LockPeriod};
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions},
+};
+use crate::{StakingError, UserAccount, StakeEntry, LockPeriod, MAX_PENALTY_BPS};
+
+/// Mint extensions that let a third party move or lock vault funds
+const HOSTILE_MINT_EXTENSIONS: &[ExtensionType] = &[
+    ExtensionType::PermanentDelegate,
+    ExtensionType::TransferHook,
+    ExtensionType::MintCloseAuthority,
+];
 
 /// Security validations and anti-gaming mechanisms
 pub struct SecurityValidator;
@@ -12,8 +23,8 @@ impl SecurityValidator {
     ) -> Result<()> {
         // Check if user has any recent stakes that were immediately unstaked
         // This helps prevent flash loan attacks
-        for stake in &user_account.stakes {
-            if !stake.is_active && stake.lock_start > (current_time - 3600) {
+        for stake in user_account.stakes() {
+            if !stake.is_active() && stake.lock_start > (current_time - 3600) {
                 // If a stake was created and deactivated within the last hour, flag it
                 let stake_duration = if stake.lock_end <= current_time {
                     // Normal unstake after lock period
@@ -42,7 +53,7 @@ impl SecurityValidator {
         let mut operation_count = 0u32;
 
         // Count recent operations of the same type
-        for stake in &user_account.stakes {
+        for stake in user_account.stakes() {
             match operation_type {
                 OperationType::Stake => {
                     if stake.lock_start > (current_time - time_window) {
@@ -51,7 +62,7 @@ impl SecurityValidator {
                 }
                 OperationType::EmergencyUnstake => {
                     // Check if this was an emergency unstake (ended before lock_end)
-                    if !stake.is_active && 
+                    if !stake.is_active() && 
                        stake.lock_start > (current_time - time_window) &&
                        current_time < stake.lock_end {
                         operation_count += 1;
@@ -86,11 +97,7 @@ impl SecurityValidator {
 
         // Allow small rounding differences (up to 0.1% variance)
         let variance_threshold = std::cmp::max(expected_rewards / 1000, 1);
-        let difference = if calculated_rewards > expected_rewards {
-            calculated_rewards - expected_rewards
-        } else {
-            expected_rewards - calculated_rewards
-        };
+        let difference = calculated_rewards.abs_diff(expected_rewards);
 
         require!(
             difference <= variance_threshold,
@@ -106,7 +113,7 @@ impl SecurityValidator {
         reward_rate: u64,
         current_time: i64,
     ) -> Result<u64> {
-        if !stake.is_active {
+        if !stake.is_active() {
             return Ok(0);
         }
 
@@ -133,8 +140,8 @@ impl SecurityValidator {
         let mut calculated_total_staked = 0u64;
         let mut active_stakes = 0u32;
 
-        for stake in &user_account.stakes {
-            if stake.is_active {
+        for stake in user_account.stakes() {
+            if stake.is_active() {
                 calculated_total_staked += stake.amount;
                 active_stakes += 1;
 
@@ -144,7 +151,7 @@ impl SecurityValidator {
                 require!(stake.multiplier > 0, StakingError::InvalidMultiplier);
 
                 // Validate multiplier matches lock period
-                let expected_multiplier = match stake.lock_period {
+                let expected_multiplier = match stake.lock_period()? {
                     LockPeriod::OneDay => 1000,
                     LockPeriod::OneWeek => 1250,
                     LockPeriod::ThreeMonths => 2000,
@@ -180,8 +187,8 @@ impl SecurityValidator {
         
         let short_term_count = existing_stakes.iter()
             .filter(|stake| {
-                stake.is_active && 
-                matches!(stake.lock_period, LockPeriod::OneDay | LockPeriod::OneWeek) &&
+                stake.is_active() && 
+                matches!(stake.lock_period(), Ok(LockPeriod::OneDay | LockPeriod::OneWeek)) &&
                 stake.lock_start > (current_time - 86400 * 7) // Created in last 7 days
             })
             .count();
@@ -199,29 +206,40 @@ impl SecurityValidator {
         Ok(())
     }
 
-    /// Validate penalty calculations for emergency unstaking
+    /// Bound an emergency unstake penalty by the stake's locked-in terms: whatever the
+    /// curve, a running lock pays between its floor and max
     pub fn validate_penalty_calculation(
         stake: &StakeEntry,
         current_time: i64,
-        calculated_penalty_percent: u64,
+        calculated_penalty_bps: u64,
     ) -> Result<()> {
         require!(current_time < stake.lock_end, StakingError::LockExpired);
+        require!(
+            calculated_penalty_bps >= stake.penalty.floor_bps as u64
+                && calculated_penalty_bps <= stake.penalty.max_bps as u64,
+            StakingError::PenaltyCalculationError
+        );
+        require!(calculated_penalty_bps <= MAX_PENALTY_BPS as u64, StakingError::ExcessivePenalty);
 
-        let total_duration = (stake.lock_end - stake.lock_start) as u64;
-        let time_remaining = (stake.lock_end - current_time) as u64;
+        Ok(())
+    }
 
-        // Calculate expected penalty percentage
-        let expected_penalty_percent = (time_remaining * 33) / total_duration;
+    /// Reject Token-2022 mints carrying extensions that could drain or freeze pool vaults
+    /// (permanent delegate, transfer hooks, close authority) unless the mint is in `allowlist`
+    pub fn validate_mint_extensions(mint: &AccountInfo, allowlist: &[Pubkey]) -> Result<()> {
+        if *mint.owner != spl_token_2022::ID || allowlist.contains(mint.key) {
+            return Ok(());
+        }
 
-        // Allow for small rounding differences
-        let difference = if calculated_penalty_percent > expected_penalty_percent {
-            calculated_penalty_percent - expected_penalty_percent
-        } else {
-            expected_penalty_percent - calculated_penalty_percent
-        };
+        let mint_data = mint.try_borrow_data()?;
+        let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
 
-        require!(difference <= 1, StakingError::PenaltyCalculationError);
-        require!(calculated_penalty_percent <= 33, StakingError::ExcessivePenalty);
+        for extension in mint_state.get_extension_types()? {
+            require!(
+                !HOSTILE_MINT_EXTENSIONS.contains(&extension),
+                StakingError::UnsupportedMintExtension
+            );
+        }
 
         Ok(())
     }
@@ -230,13 +248,14 @@ impl SecurityValidator {
     pub fn validate_sybil_protection(
         user_account: &UserAccount,
         stake_amount: u64,
+        token_unit: u64,
         current_time: i64,
     ) -> Result<()> {
         // Detect patterns that might indicate Sybil attacks:
         // - Many small stakes created in rapid succession
         // - Identical stake amounts across multiple accounts (handled at higher level)
         
-        let recent_stakes: Vec<&StakeEntry> = user_account.stakes.iter()
+        let recent_stakes: Vec<&StakeEntry> = user_account.stakes().iter()
             .filter(|stake| stake.lock_start > (current_time - 3600)) // Last hour
             .collect();
 
@@ -245,11 +264,7 @@ impl SecurityValidator {
             let first_amount = recent_stakes[0].amount;
             let similar_amounts = recent_stakes.iter()
                 .filter(|stake| {
-                    let diff = if stake.amount > first_amount {
-                        stake.amount - first_amount
-                    } else {
-                        first_amount - stake.amount
-                    };
+                    let diff = stake.amount.abs_diff(first_amount);
                     diff < (first_amount / 100) // Within 1% of each other
                 })
                 .count();
@@ -260,7 +275,7 @@ impl SecurityValidator {
         }
 
         // Flag very small stakes that might be used for gaming
-        let min_meaningful_stake = 10_000_000; // 10 tokens minimum
+        let min_meaningful_stake = 10 * token_unit; // 10 tokens minimum
         if stake_amount < min_meaningful_stake && recent_stakes.len() > 1 {
             return err!(StakingError::StakeAmountTooSmall);
         }
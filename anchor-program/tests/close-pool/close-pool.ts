@@ -0,0 +1,220 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { ZkPoopStaking } from "../../target/types/zk_poop_staking";
+import { PublicKey, SystemProgram, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
+import {
+  TOKEN_PROGRAM_ID,
+  createMint,
+  createAccount,
+  mintTo,
+  transfer,
+  getAccount,
+} from "@solana/spl-token";
+import { expect } from "chai";
+
+// Closing takes the pool PDA down, so it runs against its own validator
+describe("zk-poop-staking close pool", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.ZkPoopStaking as Program<ZkPoopStaking>;
+  const authority = provider.wallet as anchor.Wallet;
+
+  let stakingMint: PublicKey;
+  let rewardMint: PublicKey;
+  let stakingPool: PublicKey;
+  let stakingVault: PublicKey;
+  let rewardVault: PublicKey;
+  let userAccount: PublicKey;
+  let unlockQueue: PublicKey;
+  let userTokenAccount: PublicKey;
+  let treasuryAccount: PublicKey;
+  let stakingDestination: PublicKey;
+  let rewardDestination: PublicKey;
+
+  const STAKING_POOL_SEED = "staking_pool";
+  const USER_ACCOUNT_SEED = "user_account";
+  const STAKING_VAULT_SEED = "staking_vault";
+  const REWARD_VAULT_SEED = "reward_vault";
+  const UNLOCK_QUEUE_SEED = "unlock_queue";
+
+  const closePool = () =>
+    program.methods
+      .closePool()
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+        stakingVault,
+        rewardVault,
+        stakingDestination,
+        rewardDestination,
+        stakingMint,
+        rewardMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+  const expectPoolNotEmpty = async () => {
+    try {
+      await closePool();
+      expect.fail("Should have failed with PoolNotEmpty error");
+    } catch (error) {
+      expect(error.message).to.include("PoolNotEmpty");
+    }
+  };
+
+  before(async () => {
+    stakingMint = await createMint(provider.connection, authority.payer, authority.publicKey, null, 9);
+    rewardMint = await createMint(provider.connection, authority.payer, authority.publicKey, null, 9);
+
+    userTokenAccount = await createAccount(provider.connection, authority.payer, stakingMint, authority.publicKey);
+    await mintTo(provider.connection, authority.payer, stakingMint, userTokenAccount, authority.publicKey, 1005 * 10**9);
+
+    treasuryAccount = await createAccount(
+      provider.connection,
+      authority.payer,
+      stakingMint,
+      authority.publicKey,
+      anchor.web3.Keypair.generate()
+    );
+    stakingDestination = await createAccount(
+      provider.connection,
+      authority.payer,
+      stakingMint,
+      authority.publicKey,
+      anchor.web3.Keypair.generate()
+    );
+    rewardDestination = await createAccount(provider.connection, authority.payer, rewardMint, authority.publicKey);
+
+    [stakingPool] = PublicKey.findProgramAddressSync([Buffer.from(STAKING_POOL_SEED)], program.programId);
+    [stakingVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(STAKING_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    [rewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(REWARD_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    [userAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from(USER_ACCOUNT_SEED), authority.publicKey.toBuffer()],
+      program.programId
+    );
+    [unlockQueue] = PublicKey.findProgramAddressSync(
+      [Buffer.from(UNLOCK_QUEUE_SEED), authority.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializePool(new anchor.BN(2500000))
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+        stakingMint,
+        rewardMint,
+        stakingVault,
+        rewardVault,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
+
+    await program.methods
+      .setUnlockCooldown(1)
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+    // Entry 0 leaves through the unlock queue, entry 1 through an emergency unstake
+    for (let i = 0; i < 2; i++) {
+      await program.methods
+        .stake(new anchor.BN(500 * 10**9), { threeMonths: {} })
+        .accounts({
+          stakingPool,
+          userAccount,
+          authority: authority.publicKey,
+          userTokenAccount,
+          stakingVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+
+    await mintTo(provider.connection, authority.payer, rewardMint, rewardVault, authority.publicKey, 1000 * 10**9);
+  });
+
+  it("Rejects closing a pool that still owes stakers their tokens", async () => {
+    await expectPoolNotEmpty();
+
+    await program.methods
+      .emergencyUnstake(1)
+      .accounts({
+        stakingPool,
+        userAccount,
+        authority: authority.publicKey,
+        userTokenAccount,
+        stakingVault,
+        treasuryAccount,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    await program.methods
+      .requestUnlock(0)
+      .accounts({
+        stakingPool,
+        userAccount,
+        unlockQueue,
+        authority: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    // Nothing is staked any more, but the unlocked entry is still in the vault
+    const pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.totalStaked.toString()).to.equal("0");
+    expect(pool.unlocking.toString()).to.equal((500n * 10n**9n).toString());
+    await expectPoolNotEmpty();
+  });
+
+  it("Sweeps what is left in the vaults and closes the pool", async () => {
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await program.methods
+      .withdrawUnlocked()
+      .accounts({
+        stakingPool,
+        unlockQueue,
+        authority: authority.publicKey,
+        userTokenAccount,
+        stakingVault,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    // A donation lands next to the emergency penalty's rewards share
+    await transfer(provider.connection, authority.payer, userTokenAccount, stakingVault, authority.publicKey, 5 * 10**9);
+
+    const pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.unlocking.toString()).to.equal("0");
+    const stakingLeft = (await getAccount(provider.connection, stakingVault)).amount;
+    const rewardLeft = (await getAccount(provider.connection, rewardVault)).amount;
+    expect(stakingLeft).to.equal(BigInt(pool.penaltyRewards.toString()) + 5n * 10n**9n);
+    expect(rewardLeft > 0n).to.be.true;
+
+    await closePool();
+
+    expect(await provider.connection.getAccountInfo(stakingPool)).to.be.null;
+    expect(await provider.connection.getAccountInfo(stakingVault)).to.be.null;
+    expect(await provider.connection.getAccountInfo(rewardVault)).to.be.null;
+    expect((await getAccount(provider.connection, stakingDestination)).amount).to.equal(stakingLeft);
+    expect((await getAccount(provider.connection, rewardDestination)).amount).to.equal(rewardLeft);
+  });
+});
@@ -0,0 +1,175 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { ZkPoopStaking } from "../../target/types/zk_poop_staking";
+import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
+import { expect } from "chai";
+
+// Runs against a validator whose pool PDA holds the pre-versioning (v0) pool fixture
+// instead of a pool created by initialize_pool (see tests/migration/Test.toml)
+describe("zk-poop-staking migration", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.ZkPoopStaking as Program<ZkPoopStaking>;
+  const authority = provider.wallet as anchor.Wallet;
+
+  const STAKING_POOL_SEED = "staking_pool";
+
+  const [v0Pool] = PublicKey.findProgramAddressSync(
+    [Buffer.from(STAKING_POOL_SEED)],
+    program.programId
+  );
+  const v0StakingMint = new PublicKey("Hcm5mxzAbDKGb4P3SR344WRVLu4XWJirJtY31MFzc2GP");
+  const v0PoolAuthority = Keypair.fromSeed(new Uint8Array(32).fill(7));
+
+  it("Migrates a v0 pool fixture to the current layout", async () => {
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(v0PoolAuthority.publicKey, anchor.web3.LAMPORTS_PER_SOL),
+      "confirmed"
+    );
+
+    const before = await provider.connection.getAccountInfo(v0Pool);
+    expect(before.data.length).to.equal(186);
+
+    await program.methods
+      .migratePool()
+      .accounts({
+        stakingPool: v0Pool,
+        authority: v0PoolAuthority.publicKey,
+        stakingMint: v0StakingMint,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([v0PoolAuthority])
+      .rpc();
+
+    const after = await provider.connection.getAccountInfo(v0Pool);
+    expect(after.data.length).to.equal(program.account.stakingPool.size);
+
+    const migrated = await program.account.stakingPool.fetch(v0Pool);
+    expect(migrated.version).to.equal(1);
+    expect(migrated.authority.toString()).to.equal(v0PoolAuthority.publicKey.toString());
+    expect(migrated.stakingMint.toString()).to.equal(v0StakingMint.toString());
+    expect(migrated.rewardMint.toString()).to.equal("Acd5BSy8sowaNAHhne5oK27g3XsXPkSrtAC9MKDJufz4");
+    expect(migrated.stakingVault.toString()).to.equal("4krPEDReWybyhFr8UaETwnNUSW5WCRbFMUvANWck9Mh7");
+    expect(migrated.rewardVault.toString()).to.equal("8SibgaMc19m5xb94V8JEgmHDJrGtXyvoH9qAb1RHs2Ae");
+    expect(migrated.totalStaked.toString()).to.equal((250n * 10n**9n).toString());
+    expect(migrated.rewardRate.toString()).to.equal("1000");
+    expect(migrated.bump).to.equal(255);
+    expect(migrated.paused).to.equal(0);
+    expect(migrated.stakingDecimals).to.equal(9);
+    expect(migrated.minStakes.map((m) => m.toString())).to.deep.equal([
+      (100n * 10n**9n).toString(),
+      (250n * 10n**9n).toString(),
+      (500n * 10n**9n).toString(),
+      (1000n * 10n**9n).toString(),
+    ]);
+    expect(migrated.receiptMint.toString()).to.equal(PublicKey.default.toString());
+    expect(migrated.penaltyConfigs[0].maxBps).to.equal(3300);
+    // Existing stake is not in the per-tier totals until its user accounts migrate
+    expect(migrated.tierStaked.map((t) => t.toString())).to.deep.equal(["0", "0", "0", "0"]);
+    expect(migrated.unmigratedStaked.toString()).to.equal(migrated.totalStaked.toString());
+
+    // Tier capacities wait for the backfill
+    try {
+      await program.methods
+        .setStakeCaps(new anchor.BN(0), new anchor.BN(0), [
+          new anchor.BN(0),
+          new anchor.BN(500 * 10**9),
+          new anchor.BN(0),
+          new anchor.BN(0),
+        ])
+        .accounts({
+          stakingPool: v0Pool,
+          authority: v0PoolAuthority.publicKey,
+        })
+        .signers([v0PoolAuthority])
+        .rpc();
+
+      expect.fail("Should have failed with TierBackfillPending error");
+    } catch (error) {
+      expect(error.message).to.include("TierBackfillPending");
+    }
+
+    // A second migration is rejected
+    try {
+      await program.methods
+        .migratePool()
+        .accounts({
+          stakingPool: v0Pool,
+          authority: v0PoolAuthority.publicKey,
+          stakingMint: v0StakingMint,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([v0PoolAuthority])
+        .rpc();
+
+      expect.fail("Should have failed with AlreadyMigrated error");
+    } catch (error) {
+      expect(error.message).to.include("AlreadyMigrated");
+    }
+  });
+
+  it("Migrates a v0 user account fixture and backfills its pool's tier totals", async () => {
+    // Its only stake is the whole total_staked of the v0 pool fixture migrated above
+    const v0UserAccount = new PublicKey("JBmbeDKehgppvu5HGg54gNx43RpCJz6A8ZFtHJQ1WfAz");
+    const v0Authority = new PublicKey("Dub1YqGStUMjxvTKgxm8Uri8PjarPDM6V5EK6mvcCn9C");
+
+    const before = await provider.connection.getAccountInfo(v0UserAccount);
+    expect(before.data.length).to.equal(409);
+
+    await program.methods
+      .migrateUser()
+      .accounts({
+        userAccount: v0UserAccount,
+        stakingPool: v0Pool,
+        payer: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const migrated = await program.account.userAccount.fetch(v0UserAccount);
+    expect(migrated.version).to.equal(1);
+    expect(migrated.authority.toString()).to.equal(v0Authority.toString());
+    expect(migrated.totalStaked.toString()).to.equal((250n * 10n**9n).toString());
+    expect(migrated.stakeCount).to.equal(1);
+    expect(migrated.stakes[0].multiplier.toString()).to.equal("1250");
+    expect(migrated.autoCompound).to.equal(0);
+    expect(migrated.delegatePermissions).to.equal(0);
+
+    // The one-week stake now counts toward its tier, completing the backfill
+    const pool = await program.account.stakingPool.fetch(v0Pool);
+    expect(pool.tierStaked.map((t) => t.toString())).to.deep.equal(["0", (250n * 10n**9n).toString(), "0", "0"]);
+    expect(pool.unmigratedStaked.toString()).to.equal("0");
+
+    await program.methods
+      .setStakeCaps(new anchor.BN(0), new anchor.BN(0), [
+        new anchor.BN(0),
+        new anchor.BN(500 * 10**9),
+        new anchor.BN(0),
+        new anchor.BN(0),
+      ])
+      .accounts({
+        stakingPool: v0Pool,
+        authority: v0PoolAuthority.publicKey,
+      })
+      .signers([v0PoolAuthority])
+      .rpc();
+
+    // A second migration is rejected
+    try {
+      await program.methods
+        .migrateUser()
+        .accounts({
+          userAccount: v0UserAccount,
+          stakingPool: v0Pool,
+          payer: authority.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      expect.fail("Should have failed with AlreadyMigrated error");
+    } catch (error) {
+      expect(error.message).to.include("AlreadyMigrated");
+    }
+  });
+});
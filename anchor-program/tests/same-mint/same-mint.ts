@@ -0,0 +1,375 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { ZkPoopStaking } from "../../target/types/zk_poop_staking";
+import { PublicKey, Keypair, SystemProgram, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
+import {
+  TOKEN_PROGRAM_ID,
+  createMint,
+  createAccount,
+  mintTo,
+  getAccount,
+  getMint,
+} from "@solana/spl-token";
+import { expect } from "chai";
+
+// Runs against its own validator with a pool that pays rewards in the staking token,
+// which flexible staking and compounding need
+describe("zk-poop-staking same-mint pool", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.ZkPoopStaking as Program<ZkPoopStaking>;
+  const authority = provider.wallet as anchor.Wallet;
+
+  let stakingMint: PublicKey;
+  let stakingPool: PublicKey;
+  let stakingVault: PublicKey;
+  let rewardVault: PublicKey;
+  let receiptMint: PublicKey;
+
+  const STAKING_POOL_SEED = "staking_pool";
+  const USER_ACCOUNT_SEED = "user_account";
+  const RECEIPT_MINT_SEED = "receipt_mint";
+  const STAKING_VAULT_SEED = "staking_vault";
+  const REWARD_VAULT_SEED = "reward_vault";
+
+  // 1000 staked tokens earn one token per second at 1x
+  const REWARD_RATE = new anchor.BN(86_400_000_000);
+  const TOKEN = 10n ** 9n;
+
+  const balance = async (tokenAccount: PublicKey) =>
+    (await getAccount(provider.connection, tokenAccount)).amount;
+
+  // Fund a fresh staker with `amount` whole tokens, staking all of them when a lock period is given
+  const setupStaker = async (amount: number, lockPeriod?: object) => {
+    const staker = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(staker.publicKey, anchor.web3.LAMPORTS_PER_SOL),
+      "confirmed"
+    );
+
+    const tokenAccount = await createAccount(provider.connection, authority.payer, stakingMint, staker.publicKey);
+    await mintTo(provider.connection, authority.payer, stakingMint, tokenAccount, authority.publicKey, amount * 10**9);
+
+    const [userAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from(USER_ACCOUNT_SEED), staker.publicKey.toBuffer()],
+      program.programId
+    );
+
+    if (lockPeriod) {
+      await program.methods
+        .stake(new anchor.BN(amount * 10**9), lockPeriod)
+        .accounts({
+          stakingPool,
+          userAccount,
+          authority: staker.publicKey,
+          userTokenAccount: tokenAccount,
+          stakingVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([staker])
+        .rpc();
+    }
+
+    return { staker, tokenAccount, userAccount };
+  };
+
+  // Fund a fresh staker with `amount` whole tokens and a receipt token account
+  const setupFlexible = async (amount = 1000) => {
+    const { staker, tokenAccount } = await setupStaker(amount);
+    const receiptAccount = await createAccount(provider.connection, authority.payer, receiptMint, staker.publicKey);
+    return { staker, tokenAccount, receiptAccount };
+  };
+
+  const stakeFlexible = (staker: Keypair, tokenAccount: PublicKey, receiptAccount: PublicKey, amount: bigint) =>
+    program.methods
+      .stakeFlexible(new anchor.BN(amount.toString()))
+      .accounts({
+        stakingPool,
+        authority: staker.publicKey,
+        userTokenAccount: tokenAccount,
+        userReceiptAccount: receiptAccount,
+        receiptMint,
+        stakingVault,
+        rewardVault,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([staker])
+      .rpc();
+
+  before(async () => {
+    stakingMint = await createMint(provider.connection, authority.payer, authority.publicKey, null, 9);
+
+    [stakingPool] = PublicKey.findProgramAddressSync([Buffer.from(STAKING_POOL_SEED)], program.programId);
+    [stakingVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(STAKING_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    [rewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(REWARD_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    [receiptMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from(RECEIPT_MINT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializePool(REWARD_RATE)
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+        stakingMint,
+        rewardMint: stakingMint,
+        stakingVault,
+        rewardVault,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
+
+    await mintTo(provider.connection, authority.payer, stakingMint, rewardVault, authority.publicKey, 100_000 * 10**9);
+  });
+
+  it("Stakes and redeems flexibly at the receipt exchange rate", async () => {
+    await program.methods
+      .initializeReceiptMint()
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+        stakingMint,
+        receiptMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
+
+    const pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.receiptMint.toString()).to.equal(receiptMint.toString());
+
+    // The first staker mints receipts 1:1
+    const first = await setupFlexible();
+    await stakeFlexible(first.staker, first.tokenAccount, first.receiptAccount, 1000n * TOKEN);
+    expect(await balance(first.receiptAccount)).to.equal(1000n * TOKEN);
+    expect(await balance(first.tokenAccount)).to.equal(0n);
+    let poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.flexibleStaked.toString()).to.equal((1000n * TOKEN).toString());
+    expect(poolAccount.totalStaked.toString()).to.equal((1000n * TOKEN).toString());
+
+    // Base rate rewards accrue into the backing, so a later staker gets fewer receipts
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+    const rewardVaultBefore = await balance(rewardVault);
+    const second = await setupFlexible();
+    await stakeFlexible(second.staker, second.tokenAccount, second.receiptAccount, 1000n * TOKEN);
+
+    poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    const accrued = BigInt(poolAccount.flexibleStaked.toString()) - 2000n * TOKEN;
+    expect(accrued > 0n).to.be.true;
+    expect(rewardVaultBefore - (await balance(rewardVault))).to.equal(accrued);
+    expect(await balance(stakingVault)).to.equal(2000n * TOKEN + accrued);
+
+    // receipts = amount * supply / backing, at the backing before this deposit
+    const secondReceipts = await balance(second.receiptAccount);
+    expect(secondReceipts).to.equal((1000n * TOKEN * 1000n * TOKEN) / (1000n * TOKEN + accrued));
+    expect(secondReceipts < 1000n * TOKEN).to.be.true;
+
+    // Redeeming returns principal plus the rewards accrued while staked
+    await program.methods
+      .unstakeFlexible(new anchor.BN((1000n * TOKEN).toString()))
+      .accounts({
+        stakingPool,
+        authority: first.staker.publicKey,
+        userTokenAccount: first.tokenAccount,
+        userReceiptAccount: first.receiptAccount,
+        receiptMint,
+        stakingVault,
+        rewardVault,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([first.staker])
+      .rpc();
+
+    const redeemed = await balance(first.tokenAccount);
+    expect(redeemed >= 1000n * TOKEN + accrued).to.be.true;
+    expect(await balance(first.receiptAccount)).to.equal(0n);
+    expect((await getMint(provider.connection, receiptMint)).supply).to.equal(secondReceipts);
+
+    // The remaining receipts are backed by everything left in the flexible pool
+    poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(await balance(stakingVault)).to.equal(BigInt(poolAccount.flexibleStaked.toString()));
+    expect(poolAccount.totalStaked.toString()).to.equal(poolAccount.flexibleStaked.toString());
+  });
+
+  it("Counts held receipts toward the per-user cap on flexible stakes", async () => {
+    const setCaps = (perUser: bigint, total: bigint) =>
+      program.methods
+        .setStakeCaps(new anchor.BN(perUser.toString()), new anchor.BN(total.toString()), [
+          new anchor.BN(0),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          new anchor.BN(0),
+        ])
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    const { staker, tokenAccount, receiptAccount } = await setupFlexible(2000);
+    const stakeCapped = async (amount: bigint, expectedError: string) => {
+      try {
+        await stakeFlexible(staker, tokenAccount, receiptAccount, amount);
+        expect.fail(`Should have failed with ${expectedError} error`);
+      } catch (error) {
+        expect(error.message).to.include(expectedError);
+      }
+    };
+
+    await setCaps(1500n * TOKEN, 0n);
+    await stakeFlexible(staker, tokenAccount, receiptAccount, 1000n * TOKEN);
+
+    // The receipts already held redeem for about the 1000 tokens staked
+    await stakeCapped(600n * TOKEN, "UserCapExceeded");
+
+    // The pool-wide cap applies as well
+    const pool = await program.account.stakingPool.fetch(stakingPool);
+    await setCaps(0n, BigInt(pool.totalStaked.toString()) + 100n * TOKEN);
+    await stakeCapped(600n * TOKEN, "PoolCapExceeded");
+
+    await setCaps(0n, 0n);
+    await stakeFlexible(staker, tokenAccount, receiptAccount, 600n * TOKEN);
+  });
+
+  it("Compounds pending rewards into a locked stake entry", async () => {
+    const { staker, userAccount } = await setupStaker(1000, { threeMonths: {} });
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+
+    const rewardVaultBefore = await balance(rewardVault);
+    const stakingVaultBefore = await balance(stakingVault);
+    const poolBefore = await program.account.stakingPool.fetch(stakingPool);
+
+    await program.methods
+      .compound(0)
+      .accounts({
+        stakingPool,
+        userAccount,
+        operator: staker.publicKey,
+        stakingVault,
+        rewardVault,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([staker])
+      .rpc();
+
+    // The rewards move from the reward vault into the staking vault as principal
+    const compounded = rewardVaultBefore - (await balance(rewardVault));
+    expect(compounded >= TOKEN).to.be.true;
+    expect((await balance(stakingVault)) - stakingVaultBefore).to.equal(compounded);
+
+    const userAccountData = await program.account.userAccount.fetch(userAccount);
+    expect(userAccountData.pendingRewards.toString()).to.equal("0");
+    expect(userAccountData.stakes[0].amount.toString()).to.equal((1000n * TOKEN + compounded).toString());
+    expect(userAccountData.totalStaked.toString()).to.equal((1000n * TOKEN + compounded).toString());
+
+    const poolAfter = await program.account.stakingPool.fetch(stakingPool);
+    expect(BigInt(poolAfter.totalStaked.toString()) - BigInt(poolBefore.totalStaked.toString())).to.equal(compounded);
+    expect(BigInt(poolAfter.tierStaked[2].toString()) - BigInt(poolBefore.tierStaked[2].toString())).to.equal(compounded);
+  });
+
+  it("Pays the keeper bounty for due compounds within the cap and budget", async () => {
+    // 1 token per entry serviced, at most 2 per call, 3 in total
+    await program.methods
+      .setCrankConfig(
+        new anchor.BN((1n * TOKEN).toString()),
+        new anchor.BN(3600),
+        new anchor.BN((2n * TOKEN).toString()),
+        new anchor.BN((3n * TOKEN).toString())
+      )
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+    const stakers: PublicKey[] = [];
+    for (let i = 0; i < 5; i++) {
+      const { staker, userAccount } = await setupStaker(1000, { threeMonths: {} });
+      await program.methods
+        .setAutoCompound(true)
+        .accounts({
+          userAccount,
+          authority: staker.publicKey,
+        })
+        .signers([staker])
+        .rpc();
+      stakers.push(userAccount);
+    }
+    // At 2x, each entry earns two tokens a second, past the one-token compound minimum
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    const keeper = Keypair.generate();
+    const keeperRewardAccount = await createAccount(provider.connection, authority.payer, stakingMint, keeper.publicKey);
+    const crank = (userAccounts: PublicKey[]) =>
+      program.methods
+        .crank()
+        .accounts({
+          stakingPool,
+          cranker: keeper.publicKey,
+          crankerRewardAccount: keeperRewardAccount,
+          stakingVault,
+          rewardVault,
+          stakingMint,
+          rewardMint: stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(userAccounts.map((pubkey) => ({ pubkey, isWritable: true, isSigner: false })))
+        .signers([keeper])
+        .rpc();
+    const budget = async () =>
+      BigInt((await program.account.stakingPool.fetch(stakingPool)).crankBountyBudget.toString());
+
+    // Three compounds earn 3 tokens, capped at 2 for the call
+    await crank(stakers.slice(0, 3));
+    for (const userAccount of stakers.slice(0, 3)) {
+      const userAccountData = await program.account.userAccount.fetch(userAccount);
+      expect(BigInt(userAccountData.stakes[0].amount.toString()) > 1000n * TOKEN).to.be.true;
+      expect(userAccountData.pendingRewards.toString()).to.equal("0");
+    }
+    expect(await balance(keeperRewardAccount)).to.equal(2n * TOKEN);
+    expect(await budget()).to.equal(1n * TOKEN);
+
+    // Two more compounds earn 2 tokens, but only 1 is left in the budget
+    await crank(stakers.slice(3));
+    expect(await balance(keeperRewardAccount)).to.equal(3n * TOKEN);
+    expect(await budget()).to.equal(0n);
+
+    // Cranking the first users again inside the interval does no work and pays nothing
+    await program.methods
+      .setCrankConfig(
+        new anchor.BN((1n * TOKEN).toString()),
+        new anchor.BN(3600),
+        new anchor.BN((2n * TOKEN).toString()),
+        new anchor.BN((3n * TOKEN).toString())
+      )
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+      })
+      .rpc();
+    const before = await program.account.userAccount.fetch(stakers[0]);
+    await crank(stakers.slice(0, 3));
+    const after = await program.account.userAccount.fetch(stakers[0]);
+    expect(after.stakes[0].amount.toString()).to.equal(before.stakes[0].amount.toString());
+    expect(after.lastCrankTime.toString()).to.equal(before.lastCrankTime.toString());
+    expect(await balance(keeperRewardAccount)).to.equal(3n * TOKEN);
+    expect(await budget()).to.equal(3n * TOKEN);
+  });
+});
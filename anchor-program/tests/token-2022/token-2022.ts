@@ -0,0 +1,217 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { ZkPoopStaking } from "../../target/types/zk_poop_staking";
+import {
+  PublicKey,
+  Keypair,
+  SystemProgram,
+  SYSVAR_RENT_PUBKEY,
+  Transaction,
+  sendAndConfirmTransaction,
+} from "@solana/web3.js";
+import {
+  TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
+  ExtensionType,
+  createMint,
+  createAccount,
+  createInitializeMintInstruction,
+  createInitializePermanentDelegateInstruction,
+  createInitializeTransferFeeConfigInstruction,
+  getAccount,
+  getMintLen,
+  mintTo,
+} from "@solana/spl-token";
+import { expect } from "chai";
+
+// Runs against its own validator with a pool staking a Token-2022 mint that charges a
+// transfer fee and has a permanent delegate
+describe("zk-poop-staking Token-2022 mints", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.ZkPoopStaking as Program<ZkPoopStaking>;
+  const authority = provider.wallet as anchor.Wallet;
+
+  let stakingMint: PublicKey;
+  let rewardMint: PublicKey;
+  let stakingPool: PublicKey;
+  let stakingVault: PublicKey;
+  let rewardVault: PublicKey;
+  let userAccount: PublicKey;
+  let userTokenAccount: PublicKey;
+
+  const STAKING_POOL_SEED = "staking_pool";
+  const USER_ACCOUNT_SEED = "user_account";
+  const STAKING_VAULT_SEED = "staking_vault";
+  const REWARD_VAULT_SEED = "reward_vault";
+
+  const TRANSFER_FEE_BPS = 100;
+  const TOKEN = 10n ** 9n;
+
+  const stake = (amount: bigint) =>
+    program.methods
+      .stake(new anchor.BN(amount.toString()), { oneDay: {} })
+      .accounts({
+        stakingPool,
+        userAccount,
+        authority: authority.publicKey,
+        userTokenAccount,
+        stakingVault,
+        stakingMint,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+  const setExtensionAllowlist = (mints: PublicKey[]) =>
+    program.methods
+      .setExtensionAllowlist(mints)
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+  before(async () => {
+    const mintKeypair = Keypair.generate();
+    stakingMint = mintKeypair.publicKey;
+    const mintLen = getMintLen([ExtensionType.TransferFeeConfig, ExtensionType.PermanentDelegate]);
+    await sendAndConfirmTransaction(
+      provider.connection,
+      new Transaction().add(
+        SystemProgram.createAccount({
+          fromPubkey: authority.publicKey,
+          newAccountPubkey: stakingMint,
+          space: mintLen,
+          lamports: await provider.connection.getMinimumBalanceForRentExemption(mintLen),
+          programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        createInitializeTransferFeeConfigInstruction(
+          stakingMint,
+          authority.publicKey,
+          authority.publicKey,
+          TRANSFER_FEE_BPS,
+          BigInt(Number.MAX_SAFE_INTEGER),
+          TOKEN_2022_PROGRAM_ID
+        ),
+        createInitializePermanentDelegateInstruction(stakingMint, authority.publicKey, TOKEN_2022_PROGRAM_ID),
+        createInitializeMintInstruction(stakingMint, 9, authority.publicKey, null, TOKEN_2022_PROGRAM_ID)
+      ),
+      [authority.payer, mintKeypair]
+    );
+    rewardMint = await createMint(provider.connection, authority.payer, authority.publicKey, null, 9);
+
+    userTokenAccount = await createAccount(
+      provider.connection,
+      authority.payer,
+      stakingMint,
+      authority.publicKey,
+      undefined,
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    await mintTo(
+      provider.connection,
+      authority.payer,
+      stakingMint,
+      userTokenAccount,
+      authority.publicKey,
+      1000n * TOKEN,
+      [],
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+
+    [stakingPool] = PublicKey.findProgramAddressSync([Buffer.from(STAKING_POOL_SEED)], program.programId);
+    [stakingVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(STAKING_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    [rewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(REWARD_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    [userAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from(USER_ACCOUNT_SEED), authority.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializePool(new anchor.BN(2500000))
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+        stakingMint,
+        rewardMint,
+        stakingVault,
+        rewardVault,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
+  });
+
+  it("Rejects deposits of a mint with a permanent delegate until it is allow-listed", async () => {
+    try {
+      await stake(500n * TOKEN);
+      expect.fail("Should have failed with UnsupportedMintExtension error");
+    } catch (error) {
+      expect(error.message).to.include("UnsupportedMintExtension");
+    }
+    expect((await getAccount(provider.connection, userTokenAccount, undefined, TOKEN_2022_PROGRAM_ID)).amount)
+      .to.equal(1000n * TOKEN);
+
+    // The allowlist is bounded, and only the pool authority can change it
+    try {
+      await setExtensionAllowlist(Array.from({ length: 5 }, () => Keypair.generate().publicKey));
+      expect.fail("Should have failed with ExtensionAllowlistFull error");
+    } catch (error) {
+      expect(error.message).to.include("ExtensionAllowlistFull");
+    }
+
+    const stranger = Keypair.generate();
+    try {
+      await program.methods
+        .setExtensionAllowlist([stakingMint])
+        .accounts({
+          stakingPool,
+          authority: stranger.publicKey,
+        })
+        .signers([stranger])
+        .rpc();
+      expect.fail("Should have failed with Unauthorized error");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+
+    const pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.extensionAllowlistCount).to.equal(0);
+  });
+
+  it("Credits the stake with what arrives after the transfer fee", async () => {
+    await setExtensionAllowlist([stakingMint]);
+    let pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.extensionAllowlistCount).to.equal(1);
+    expect(pool.extensionAllowlist[0].toString()).to.equal(stakingMint.toString());
+
+    const amount = 500n * TOKEN;
+    const net = amount - (amount * BigInt(TRANSFER_FEE_BPS)) / 10_000n;
+    await stake(amount);
+
+    const userAccountData = await program.account.userAccount.fetch(userAccount);
+    expect(userAccountData.stakes[0].amount.toString()).to.equal(net.toString());
+    expect(userAccountData.totalStaked.toString()).to.equal(net.toString());
+
+    pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.totalStaked.toString()).to.equal(net.toString());
+    expect(pool.tierStaked[0].toString()).to.equal(net.toString());
+
+    const vault = await getAccount(provider.connection, stakingVault, undefined, TOKEN_2022_PROGRAM_ID);
+    expect(vault.amount).to.equal(net);
+    expect((await getAccount(provider.connection, userTokenAccount, undefined, TOKEN_2022_PROGRAM_ID)).amount)
+      .to.equal(1000n * TOKEN - amount);
+  });
+});
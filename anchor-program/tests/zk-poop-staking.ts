@@ -13,8 +13,24 @@ import {
   createAccount,
   mintTo,
   getAccount,
+  getMint,
 } from "@solana/spl-token";
 import { expect } from "chai";
+import * as fs from "fs";
+import { createHash } from "crypto";
+
+// Mirrors programs/zk-poop-staking/src/merkle.rs
+const allowlistLeaf = (staker: PublicKey, maxAmount: anchor.BN): Buffer =>
+  createHash("sha256")
+    .update(Buffer.from([0]))
+    .update(staker.toBuffer())
+    .update(maxAmount.toArrayLike(Buffer, "le", 8))
+    .digest();
+
+const allowlistNode = (a: Buffer, b: Buffer): Buffer => {
+  const [left, right] = Buffer.compare(a, b) <= 0 ? [a, b] : [b, a];
+  return createHash("sha256").update(Buffer.from([1])).update(left).update(right).digest();
+};
 
 describe("zk-poop-staking", () => {
   const provider = anchor.AnchorProvider.env();
@@ -32,9 +48,64 @@ describe("zk-poop-staking", () => {
   let userTokenAccount: PublicKey;
   let userRewardAccount: PublicKey;
   let treasuryAccount: PublicKey;
+  let receiptMint: PublicKey;
 
   const STAKING_POOL_SEED = "staking_pool";
   const USER_ACCOUNT_SEED = "user_account";
+  const RECEIPT_MINT_SEED = "receipt_mint";
+  const STAKING_VAULT_SEED = "staking_vault";
+  const REWARD_VAULT_SEED = "reward_vault";
+  const BOOST_CONFIG_SEED = "boost_config";
+  const UNLOCK_QUEUE_SEED = "unlock_queue";
+  const TOKEN_METADATA_PROGRAM_ID = new PublicKey("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+
+  // Fund a fresh staker with `amount` whole tokens, staking all of them when a lock period is given
+  const setupStaker = async (amount: number, lockPeriod?: object) => {
+    const staker = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(staker.publicKey, anchor.web3.LAMPORTS_PER_SOL),
+      "confirmed"
+    );
+
+    const tokenAccount = await createAccount(
+      provider.connection,
+      authority.payer,
+      stakingMint,
+      staker.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      authority.payer,
+      stakingMint,
+      tokenAccount,
+      authority.publicKey,
+      amount * 10**9
+    );
+
+    const [stakerUserAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from(USER_ACCOUNT_SEED), staker.publicKey.toBuffer()],
+      program.programId
+    );
+
+    if (lockPeriod) {
+      await program.methods
+        .stake(new anchor.BN(amount * 10**9), lockPeriod)
+        .accounts({
+          stakingPool,
+          userAccount: stakerUserAccount,
+          authority: staker.publicKey,
+          userTokenAccount: tokenAccount,
+          stakingVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([staker])
+        .rpc();
+    }
+
+    return { staker, tokenAccount, userAccount: stakerUserAccount };
+  };
 
   before(async () => {
     // Create staking token mint
@@ -99,19 +170,37 @@ describe("zk-poop-staking", () => {
       [Buffer.from(USER_ACCOUNT_SEED), authority.publicKey.toBuffer()],
       program.programId
     );
+
+    [receiptMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from(RECEIPT_MINT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+
+    [stakingVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(STAKING_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+
+    [rewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(REWARD_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
   });
 
   it("Initializes the staking pool", async () => {
     const rewardRate = new anchor.BN(2500000); // 2.5 $WePee per 1K staked per day (scaled by 1e6)
 
     await program.methods
-      .initializePool(1, rewardRate) // bump = 1, reward_rate
+      .initializePool(rewardRate)
       .accounts({
         stakingPool,
         authority: authority.publicKey,
         stakingMint,
         rewardMint,
+        stakingVault,
+        rewardVault,
         tokenProgram: TOKEN_PROGRAM_ID,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
         rent: SYSVAR_RENT_PUBKEY,
       })
@@ -119,13 +208,38 @@ describe("zk-poop-staking", () => {
 
     const poolAccount = await program.account.stakingPool.fetch(stakingPool);
     expect(poolAccount.authority.toString()).to.equal(authority.publicKey.toString());
+    expect(poolAccount.stakingVault.toString()).to.equal(stakingVault.toString());
+    expect(poolAccount.rewardVault.toString()).to.equal(rewardVault.toString());
+
+    // Canonical bumps are stored for every pool-owned PDA
+    const [, poolBump] = PublicKey.findProgramAddressSync([Buffer.from(STAKING_POOL_SEED)], program.programId);
+    const [, stakingVaultBump] = PublicKey.findProgramAddressSync(
+      [Buffer.from(STAKING_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    const [, rewardVaultBump] = PublicKey.findProgramAddressSync(
+      [Buffer.from(REWARD_VAULT_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+    expect(poolAccount.bump).to.equal(poolBump);
+    expect(poolAccount.stakingVaultBump).to.equal(stakingVaultBump);
+    expect(poolAccount.rewardVaultBump).to.equal(rewardVaultBump);
     expect(poolAccount.rewardRate.toString()).to.equal(rewardRate.toString());
-    expect(poolAccount.paused).to.be.false;
+    expect(poolAccount.paused).to.equal(0);
+
+    // Minimums are whole-token amounts scaled by the 9-decimal staking mint
+    expect(poolAccount.stakingDecimals).to.equal(9);
+    expect(poolAccount.minStakes.map((m) => m.toString())).to.deep.equal([
+      (100n * 10n**9n).toString(),
+      (250n * 10n**9n).toString(),
+      (500n * 10n**9n).toString(),
+      (1000n * 10n**9n).toString(),
+    ]);
   });
 
   it("Initializes user account", async () => {
     await program.methods
-      .initializeUser(1) // bump = 1
+      .initializeUser()
       .accounts({
         userAccount,
         authority: authority.publicKey,
@@ -136,15 +250,11 @@ describe("zk-poop-staking", () => {
     const userAccountData = await program.account.userAccount.fetch(userAccount);
     expect(userAccountData.authority.toString()).to.equal(authority.publicKey.toString());
     expect(userAccountData.totalStaked.toString()).to.equal("0");
-    expect(userAccountData.stakes.length).to.equal(0);
+    expect(userAccountData.stakeCount).to.equal(0);
   });
 
   it("Stakes tokens with 3-month lock period", async () => {
     const stakeAmount = new anchor.BN(500 * 10**9); // 500 tokens
-    
-    // Get vault addresses from pool account
-    const poolAccount = await program.account.stakingPool.fetch(stakingPool);
-    stakingVault = poolAccount.stakingVault;
 
     await program.methods
       .stake(stakeAmount, { threeMonths: {} })
@@ -154,16 +264,17 @@ describe("zk-poop-staking", () => {
         authority: authority.publicKey,
         userTokenAccount,
         stakingVault,
+        stakingMint,
         tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc();
 
     const userAccountData = await program.account.userAccount.fetch(userAccount);
     expect(userAccountData.totalStaked.toString()).to.equal(stakeAmount.toString());
-    expect(userAccountData.stakes.length).to.equal(1);
+    expect(userAccountData.stakeCount).to.equal(1);
     expect(userAccountData.stakes[0].amount.toString()).to.equal(stakeAmount.toString());
     expect(userAccountData.stakes[0].multiplier.toString()).to.equal("2000"); // 2.0x
-    expect(userAccountData.stakes[0].isActive).to.be.true;
+    expect(userAccountData.stakes[0].isActive).to.equal(1);
 
     // Check vault balance
     const vaultAccount = await getAccount(provider.connection, stakingVault);
@@ -182,6 +293,7 @@ describe("zk-poop-staking", () => {
           authority: authority.publicKey,
           userTokenAccount,
           stakingVault,
+          stakingMint,
           tokenProgram: TOKEN_PROGRAM_ID,
         })
         .rpc();
@@ -207,13 +319,16 @@ describe("zk-poop-staking", () => {
         userTokenAccount,
         stakingVault,
         treasuryAccount,
+        stakingMint,
         tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc();
 
     const userAccountData = await program.account.userAccount.fetch(userAccount);
-    expect(userAccountData.stakes[0].isActive).to.be.false;
+    expect(userAccountData.stakes[0].isActive).to.equal(0);
     expect(userAccountData.totalStaked.toString()).to.equal("0");
+    expect(userAccountData.stakes[0].penalty.maxBps).to.equal(3_300);
+    expect(userAccountData.stakes[0].penalty.burnBps).to.equal(4_000);
 
     const userBalanceAfter = await getAccount(provider.connection, userTokenAccount);
     const vaultBalanceAfter = await getAccount(provider.connection, stakingVault);
@@ -238,6 +353,7 @@ describe("zk-poop-staking", () => {
         authority: authority.publicKey,
         userTokenAccount,
         stakingVault,
+        stakingMint,
         tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc();
@@ -247,7 +363,7 @@ describe("zk-poop-staking", () => {
     // The lock should still be active, so emergency unstake should work
     
     const userAccountData = await program.account.userAccount.fetch(userAccount);
-    const activeStakeIndex = userAccountData.stakes.findIndex(stake => stake.isActive);
+    const activeStakeIndex = userAccountData.stakes.findIndex(stake => stake.isActive === 1);
     
     if (activeStakeIndex !== -1) {
       await program.methods
@@ -259,6 +375,7 @@ describe("zk-poop-staking", () => {
           userTokenAccount,
           stakingVault,
           treasuryAccount,
+          stakingMint,
           tokenProgram: TOKEN_PROGRAM_ID,
         })
         .rpc();
@@ -276,7 +393,7 @@ describe("zk-poop-staking", () => {
       .rpc();
 
     let poolAccount = await program.account.stakingPool.fetch(stakingPool);
-    expect(poolAccount.paused).to.be.true;
+    expect(poolAccount.paused).to.equal(1);
 
     // Try to stake while paused (should fail)
     try {
@@ -288,6 +405,7 @@ describe("zk-poop-staking", () => {
           authority: authority.publicKey,
           userTokenAccount,
           stakingVault,
+          stakingMint,
           tokenProgram: TOKEN_PROGRAM_ID,
         })
         .rpc();
@@ -307,6 +425,1441 @@ describe("zk-poop-staking", () => {
       .rpc();
 
     poolAccount = await program.account.stakingPool.fetch(stakingPool);
-    expect(poolAccount.paused).to.be.false;
+    expect(poolAccount.paused).to.equal(0);
+  });
+
+  it("Rejects flexible staking when rewards are paid in a separate mint", async () => {
+    // Flexible rewards accrue into the receipt exchange rate, which only works when the
+    // pool pays rewards in the staking token
+    try {
+      await program.methods
+        .initializeReceiptMint()
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+          stakingMint,
+          receiptMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .rpc();
+
+      expect.fail("Should have failed with FlexibleMintMismatch error");
+    } catch (error) {
+      expect(error.message).to.include("FlexibleMintMismatch");
+    }
+
+    const pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.receiptMint.toString()).to.equal(PublicKey.default.toString());
+  });
+
+  it("Opts in to auto-compound and rejects compounding across mints", async () => {
+    await program.methods
+      .setAutoCompound(true)
+      .accounts({
+        userAccount,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+    const userAccountData = await program.account.userAccount.fetch(userAccount);
+    expect(userAccountData.autoCompound).to.equal(1);
+
+    // The test pool pays rewards in a separate mint, so rewards cannot become principal
+    try {
+      await program.methods
+        .autoCompound()
+        .accounts({
+          stakingPool,
+          userAccount,
+          cranker: authority.publicKey,
+          stakingVault,
+          rewardVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      expect.fail("Should have failed with CompoundMintMismatch error");
+    } catch (error) {
+      expect(error.message).to.include("CompoundMintMismatch");
+    }
+  });
+
+  it("Keeper crank services users and respects the rate limit", async () => {
+    // 1 token per entry serviced, at most 2 per call, 5 in total
+    await program.methods
+      .setCrankConfig(
+        new anchor.BN(10**9),
+        new anchor.BN(3600),
+        new anchor.BN(2 * 10**9),
+        new anchor.BN(5 * 10**9)
+      )
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+    // The bounty may not exceed the per-call cap
+    try {
+      await program.methods
+        .setCrankConfig(new anchor.BN(3 * 10**9), new anchor.BN(3600), new anchor.BN(2 * 10**9), new anchor.BN(0))
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+      expect.fail("Should have failed with InvalidCrankConfig error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidCrankConfig");
+    }
+
+    const rewardBalanceBefore = await getAccount(provider.connection, userRewardAccount);
+
+    const crank = () =>
+      program.methods
+        .crank()
+        .accounts({
+          stakingPool,
+          cranker: authority.publicKey,
+          crankerRewardAccount: userRewardAccount,
+          stakingVault,
+          rewardVault,
+          stakingMint,
+          rewardMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts([{ pubkey: userAccount, isWritable: true, isSigner: false }])
+        .rpc();
+
+    await crank();
+    const afterFirst = await program.account.userAccount.fetch(userAccount);
+    expect(afterFirst.lastCrankTime.toNumber()).to.be.greaterThan(0);
+
+    // Nothing was compounded or rolled over, so no bounty is paid and the budget is untouched
+    const rewardBalanceAfter = await getAccount(provider.connection, userRewardAccount);
+    expect(rewardBalanceAfter.amount).to.equal(rewardBalanceBefore.amount);
+    const pool = await program.account.stakingPool.fetch(stakingPool);
+    expect(pool.crankBountyCap.toString()).to.equal((2n * 10n**9n).toString());
+    expect(pool.crankBountyBudget.toString()).to.equal((5n * 10n**9n).toString());
+
+    // Cranking again within the interval skips the user
+    await crank();
+    const afterSecond = await program.account.userAccount.fetch(userAccount);
+    expect(afterSecond.lastCrankTime.toString()).to.equal(afterFirst.lastCrankTime.toString());
+  });
+
+  it("Only a delegate with the claim permission can claim on behalf of a user", async () => {
+    const DELEGATE_CLAIM = 1 << 0;
+    const claimer = Keypair.generate();
+
+    await program.methods
+      .setDelegate(claimer.publicKey, DELEGATE_CLAIM)
+      .accounts({
+        userAccount,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+    const userAccountData = await program.account.userAccount.fetch(userAccount);
+    expect(userAccountData.delegate.toString()).to.equal(claimer.publicKey.toString());
+    expect(userAccountData.delegatePermissions).to.equal(DELEGATE_CLAIM);
+
+    const stranger = Keypair.generate();
+    try {
+      await program.methods
+        .claimRewardsFor()
+        .accounts({
+          stakingPool,
+          userAccount,
+          claimer: stranger.publicKey,
+          ownerRewardAccount: userRewardAccount,
+          rewardVault,
+          rewardMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([stranger])
+        .rpc();
+
+      expect.fail("Should have failed with Unauthorized error");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+  });
+
+  it("Claims rewards to any destination, and into the owner's ATA as a claim delegate", async () => {
+    const DELEGATE_CLAIM = 1 << 0;
+    await mintTo(provider.connection, authority.payer, rewardMint, rewardVault, authority.publicKey, 1000 * 10**9);
+
+    const { staker, userAccount: stakerUserAccount } = await setupStaker(1000, { threeMonths: {} });
+    const ownerRewardAccount = await createAccount(provider.connection, authority.payer, rewardMint, staker.publicKey);
+    // Any reward token account will do, here one the staker does not own
+    const destination = await createAccount(
+      provider.connection,
+      authority.payer,
+      rewardMint,
+      authority.publicKey,
+      Keypair.generate()
+    );
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    // The owner claims into the arbitrary destination
+    let vaultBefore = (await getAccount(provider.connection, rewardVault)).amount;
+    await program.methods
+      .claimRewardsTo()
+      .accounts({
+        stakingPool,
+        userAccount: stakerUserAccount,
+        authority: staker.publicKey,
+        destination,
+        rewardVault,
+        rewardMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([staker])
+      .rpc();
+
+    const claimedTo = (await getAccount(provider.connection, destination)).amount;
+    expect(claimedTo > 0n).to.be.true;
+    expect(vaultBefore - (await getAccount(provider.connection, rewardVault)).amount).to.equal(claimedTo);
+    expect((await getAccount(provider.connection, ownerRewardAccount)).amount).to.equal(0n);
+    let userAccountData = await program.account.userAccount.fetch(stakerUserAccount);
+    expect(userAccountData.pendingRewards.toString()).to.equal("0");
+
+    // A claim delegate can only pay into the owner's reward ATA
+    const claimer = Keypair.generate();
+    await program.methods
+      .setDelegate(claimer.publicKey, DELEGATE_CLAIM)
+      .accounts({
+        userAccount: stakerUserAccount,
+        authority: staker.publicKey,
+      })
+      .signers([staker])
+      .rpc();
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    const claimFor = (rewardAccount: PublicKey) =>
+      program.methods
+        .claimRewardsFor()
+        .accounts({
+          stakingPool,
+          userAccount: stakerUserAccount,
+          claimer: claimer.publicKey,
+          ownerRewardAccount: rewardAccount,
+          rewardVault,
+          rewardMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([claimer])
+        .rpc();
+
+    try {
+      await claimFor(destination);
+      expect.fail("Should have failed with ConstraintAddress error");
+    } catch (error) {
+      expect(error.message).to.include("ConstraintAddress");
+    }
+
+    vaultBefore = (await getAccount(provider.connection, rewardVault)).amount;
+    await claimFor(ownerRewardAccount);
+
+    const claimedFor = (await getAccount(provider.connection, ownerRewardAccount)).amount;
+    expect(claimedFor > 0n).to.be.true;
+    expect(vaultBefore - (await getAccount(provider.connection, rewardVault)).amount).to.equal(claimedFor);
+    expect((await getAccount(provider.connection, destination)).amount).to.equal(claimedTo);
+    userAccountData = await program.account.userAccount.fetch(stakerUserAccount);
+    expect(userAccountData.pendingRewards.toString()).to.equal("0");
+  });
+
+  it("Delegates position management with a permission bitmask", async () => {
+    const DELEGATE_EXTEND_LOCK = 1 << 1;
+    const operator = Keypair.generate();
+
+    await program.methods
+      .setDelegate(operator.publicKey, DELEGATE_EXTEND_LOCK)
+      .accounts({
+        userAccount,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+    const userAccountData = await program.account.userAccount.fetch(userAccount);
+    expect(userAccountData.delegate.toString()).to.equal(operator.publicKey.toString());
+    expect(userAccountData.delegatePermissions).to.equal(DELEGATE_EXTEND_LOCK);
+
+    // The operator passes the permission check and hits the stake state check
+    try {
+      await program.methods
+        .extendLock(0, { sixMonths: {} })
+        .accounts({
+          stakingPool,
+          userAccount,
+          operator: operator.publicKey,
+        })
+        .signers([operator])
+        .rpc();
+
+      expect.fail("Should have failed with StakeNotActive error");
+    } catch (error) {
+      expect(error.message).to.include("StakeNotActive");
+    }
+
+    // Restake was not granted
+    try {
+      await program.methods
+        .restake(0, { sixMonths: {} })
+        .accounts({
+          stakingPool,
+          userAccount,
+          operator: operator.publicKey,
+        })
+        .signers([operator])
+        .rpc();
+
+      expect.fail("Should have failed with Unauthorized error");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+  });
+
+  it("Rejects migrating a pool already on the current layout", async () => {
+    try {
+      await program.methods
+        .migratePool()
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+          stakingMint,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      expect.fail("Should have failed with AlreadyMigrated error");
+    } catch (error) {
+      expect(error.message).to.include("AlreadyMigrated");
+    }
+  });
+
+  it("Creates the user account on a first stake without initialize_user", async () => {
+    const { staker: newStaker, userAccount: newUserAccount } = await setupStaker(100, { oneDay: {} });
+    const [, newUserBump] = PublicKey.findProgramAddressSync(
+      [Buffer.from(USER_ACCOUNT_SEED), newStaker.publicKey.toBuffer()],
+      program.programId
+    );
+
+    const userAccountData = await program.account.userAccount.fetch(newUserAccount);
+    expect(userAccountData.authority.toString()).to.equal(newStaker.publicKey.toString());
+    expect(userAccountData.bump).to.equal(newUserBump);
+    expect(userAccountData.stakeCount).to.equal(1);
+    expect(userAccountData.totalStaked.toString()).to.equal((100n * 10n**9n).toString());
+  });
+
+  it("Enforces per-user, pool-wide and per-tier stake caps", async () => {
+    const {
+      staker: cappedStaker,
+      tokenAccount: cappedTokenAccount,
+      userAccount: cappedUserAccount,
+    } = await setupStaker(100);
+
+    const setCaps = (perUser: number, total: anchor.BN, tiers: anchor.BN[]) =>
+      program.methods
+        .setStakeCaps(new anchor.BN(perUser), total, tiers)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    const stakeCapped = async (expectedError: string) => {
+      try {
+        await program.methods
+          .stake(new anchor.BN(100 * 10**9), { oneDay: {} })
+          .accounts({
+            stakingPool,
+            userAccount: cappedUserAccount,
+            authority: cappedStaker.publicKey,
+            userTokenAccount: cappedTokenAccount,
+            stakingVault,
+            stakingMint,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([cappedStaker])
+          .rpc();
+
+        expect.fail(`Should have failed with ${expectedError} error`);
+      } catch (error) {
+        expect(error.message).to.include(expectedError);
+      }
+    };
+
+    const zero = new anchor.BN(0);
+    const noTierCaps = [zero, zero, zero, zero];
+    let info = await program.methods.poolInfo().accounts({ stakingPool }).view();
+
+    // Per-user cap just below the 1-day minimum
+    await setCaps(99 * 10**9, zero, noTierCaps);
+    await stakeCapped("UserCapExceeded");
+
+    // Pool-wide cap at the current total
+    await setCaps(0, info.totalStaked, noTierCaps);
+    await stakeCapped("PoolCapExceeded");
+
+    // 1-day tier full at its current total
+    await setCaps(0, zero, [info.tierStaked[0], zero, zero, zero]);
+    await stakeCapped("TierCapacityExceeded");
+
+    info = await program.methods.poolInfo().accounts({ stakingPool }).view();
+    expect(info.maxStakePerUser.toNumber()).to.equal(0);
+    expect(info.maxTotalStaked.toNumber()).to.equal(0);
+    expect(info.tierCapacity[0].toString()).to.equal(info.tierStaked[0].toString());
+
+    // Lift the caps again
+    await setCaps(0, zero, noTierCaps);
+    info = await program.methods.poolInfo().accounts({ stakingPool }).view();
+    expect(info.tierCapacity.every((capacity) => capacity.isZero())).to.be.true;
+  });
+
+  it("Gates staking behind a Merkle allowlist with per-address caps", async () => {
+    const {
+      staker: allowedStaker,
+      tokenAccount: allowedTokenAccount,
+      userAccount: allowedUserAccount,
+    } = await setupStaker(200);
+
+    // Two-entry tree: the proof for each leaf is its sibling
+    const allowedCap = new anchor.BN(100 * 10**9);
+    const allowedLeaf = allowlistLeaf(allowedStaker.publicKey, allowedCap);
+    const otherLeaf = allowlistLeaf(Keypair.generate().publicKey, new anchor.BN(0));
+    const root = allowlistNode(allowedLeaf, otherLeaf);
+
+    const setRoot = (allowlistRoot: Buffer) =>
+      program.methods
+        .setAllowlistRoot([...allowlistRoot])
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    const stakeAccounts = {
+      stakingPool,
+      userAccount: allowedUserAccount,
+      authority: allowedStaker.publicKey,
+      userTokenAccount: allowedTokenAccount,
+      stakingVault,
+      stakingMint,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const stakeAmount = new anchor.BN(100 * 10**9);
+
+    await setRoot(root);
+
+    // Plain stake is closed while the allowlist is set
+    try {
+      await program.methods
+        .stake(stakeAmount, { oneDay: {} })
+        .accounts(stakeAccounts)
+        .signers([allowedStaker])
+        .rpc();
+
+      expect.fail("Should have failed with AllowlistProofRequired error");
+    } catch (error) {
+      expect(error.message).to.include("AllowlistProofRequired");
+    }
+
+    // Claiming a larger cap than the leaf commits to breaks the proof
+    try {
+      await program.methods
+        .stakeAllowlisted(stakeAmount, { oneDay: {} }, new anchor.BN(0), [[...otherLeaf]])
+        .accounts(stakeAccounts)
+        .signers([allowedStaker])
+        .rpc();
+
+      expect.fail("Should have failed with InvalidAllowlistProof error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidAllowlistProof");
+    }
+
+    await program.methods
+      .stakeAllowlisted(stakeAmount, { oneDay: {} }, allowedCap, [[...otherLeaf]])
+      .accounts(stakeAccounts)
+      .signers([allowedStaker])
+      .rpc();
+
+    const userAccountData = await program.account.userAccount.fetch(allowedUserAccount);
+    expect(userAccountData.totalStaked.toString()).to.equal(stakeAmount.toString());
+
+    const info = await program.methods.poolInfo().accounts({ stakingPool }).view();
+    expect(Buffer.from(info.allowlistRoot).equals(root)).to.be.true;
+
+    // Reopen the pool
+    await setRoot(Buffer.alloc(32));
+    const poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.allowlistRoot.every((byte) => byte === 0)).to.be.true;
+  });
+
+  it("Configures NFT boost collections and rejects unproven boosts", async () => {
+    const [boostConfig, boostConfigBump] = PublicKey.findProgramAddressSync(
+      [Buffer.from(BOOST_CONFIG_SEED), stakingPool.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeBoostConfig()
+      .accounts({
+        stakingPool,
+        boostConfig,
+        authority: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const setBoost = (collection: PublicKey, bonusBps: number) =>
+      program.methods
+        .setBoostCollection(collection, bonusBps)
+        .accounts({
+          stakingPool,
+          boostConfig,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    const firstCollection = Keypair.generate().publicKey;
+    const secondCollection = Keypair.generate().publicKey;
+    await setBoost(firstCollection, 1000); // +10%
+    await setBoost(secondCollection, 2500); // +25%
+    await setBoost(firstCollection, 0);
+
+    const config = await program.account.boostConfig.fetch(boostConfig);
+    expect(config.bump).to.equal(boostConfigBump);
+    expect(config.count).to.equal(1);
+    expect(config.collections[0].toString()).to.equal(secondCollection.toString());
+    expect(config.bonusBps[0]).to.equal(2500);
+
+    try {
+      await setBoost(firstCollection, 20_000);
+      expect.fail("Should have failed with InvalidBoost error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidBoost");
+    }
+
+    // A fungible mint is not an NFT, whatever metadata comes with it. Its decimals give it away.
+    const [nftMetadata] = PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), stakingMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    try {
+      await program.methods
+        .applyBoost()
+        .accounts({
+          stakingPool,
+          boostConfig,
+          userAccount,
+          authority: authority.publicKey,
+          nftMint: stakingMint,
+          nftTokenAccount: userTokenAccount,
+          nftMetadata,
+        })
+        .rpc();
+
+      expect.fail("Should have failed with ConstraintMintDecimals error");
+    } catch (error) {
+      expect(error.message).to.include("ConstraintMintDecimals");
+    }
+
+    // Nothing to refresh for a user without a boost
+    const userAccountData = await program.account.userAccount.fetch(userAccount);
+    expect(userAccountData.boostBps).to.equal(0);
+    try {
+      await program.methods
+        .refreshBoost()
+        .accounts({
+          stakingPool,
+          boostConfig,
+          userAccount,
+          nftTokenAccount: userAccountData.boostTokenAccount,
+        })
+        .rpc();
+
+      expect.fail("Should have failed with NoActiveBoost error");
+    } catch (error) {
+      expect(error.message).to.include("NoActiveBoost");
+    }
+  });
+
+  it("Schedules and cancels reward multiplier windows", async () => {
+    const now = Math.floor(Date.now() / 1000);
+    const start = new anchor.BN(now + 86400);
+    const end = new anchor.BN(now + 2 * 86400);
+    const threeMonthsAndSixMonths = 0b1100;
+
+    const scheduleWindow = (windowStart: anchor.BN, multiplierBps: number) =>
+      program.methods
+        .scheduleBoostWindow(windowStart, end, multiplierBps, threeMonthsAndSixMonths)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    await scheduleWindow(start, 20_000); // 2x
+
+    let poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.boostWindowCount).to.equal(1);
+    expect(poolAccount.boostWindows[0].start.toString()).to.equal(start.toString());
+    expect(poolAccount.boostWindows[0].multiplierBps).to.equal(20_000);
+    expect(poolAccount.boostWindows[0].tiers).to.equal(threeMonthsAndSixMonths);
+
+    // Windows cannot be backdated or multiply below 1x
+    for (const [windowStart, multiplierBps] of [[new anchor.BN(now - 86400), 20_000], [start, 5_000]] as const) {
+      try {
+        await scheduleWindow(windowStart, multiplierBps);
+        expect.fail("Should have failed with InvalidBoostWindow error");
+      } catch (error) {
+        expect(error.message).to.include("InvalidBoostWindow");
+      }
+    }
+
+    await program.methods
+      .cancelBoostWindow(0)
+      .accounts({
+        stakingPool,
+        authority: authority.publicKey,
+      })
+      .rpc();
+
+    poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.boostWindowCount).to.equal(0);
+  });
+
+  it("Sets an emission schedule and reports the rate at any time", async () => {
+    const poolBefore = await program.account.stakingPool.fetch(stakingPool);
+    const flatRate = poolBefore.rewardRate;
+
+    const now = Math.floor(Date.now() / 1000);
+    const startTime = now + 30 * 86400;
+    const halvingInterval = 180 * 86400; // Every 6 months
+    const initialRate = flatRate.muln(2);
+    const floorRate = flatRate.divn(4);
+
+    const setSchedule = (params: {
+      startTime: number;
+      initialRate: anchor.BN;
+      halvingInterval: number;
+      floorRate: anchor.BN;
+      segments: { start: anchor.BN; rate: anchor.BN }[];
+    }) =>
+      program.methods
+        .setEmissionSchedule({
+          startTime: new anchor.BN(params.startTime),
+          initialRate: params.initialRate,
+          halvingInterval: new anchor.BN(params.halvingInterval),
+          floorRate: params.floorRate,
+          segments: params.segments,
+        })
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    const rateAt = (timestamp: number) =>
+      program.methods.emissionRateAt(new anchor.BN(timestamp)).accounts({ stakingPool }).view();
+
+    // A table must be ascending and start at start_time
+    try {
+      await setSchedule({
+        startTime,
+        initialRate: new anchor.BN(0),
+        halvingInterval: 0,
+        floorRate: new anchor.BN(0),
+        segments: [
+          { start: new anchor.BN(startTime), rate: initialRate },
+          { start: new anchor.BN(startTime), rate: flatRate },
+        ],
+      });
+      expect.fail("Should have failed with InvalidEmissionSchedule error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidEmissionSchedule");
+    }
+
+    await setSchedule({ startTime, initialRate, halvingInterval, floorRate, segments: [] });
+
+    expect((await rateAt(now)).toString()).to.equal(flatRate.toString());
+    expect((await rateAt(startTime)).toString()).to.equal(initialRate.toString());
+    expect((await rateAt(startTime + halvingInterval)).toString()).to.equal(flatRate.toString());
+    expect((await rateAt(startTime + 2 * halvingInterval)).toString()).to.equal(flatRate.divn(2).toString());
+    expect((await rateAt(startTime + 10 * halvingInterval)).toString()).to.equal(floorRate.toString());
+
+    const info = await program.methods.poolInfo().accounts({ stakingPool }).view();
+    expect(info.emissionRate.toString()).to.equal(flatRate.toString());
+  });
+
+  it("Scales emissions with participation on a utilization curve", async () => {
+    const setCurve = (target: anchor.BN, minBps: number, maxBps: number) =>
+      program.methods
+        .setUtilizationCurve(target, minBps, maxBps)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    try {
+      await setCurve(new anchor.BN(1_000), 12_000, 30_000);
+      expect.fail("Should have failed with InvalidUtilizationCurve error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidUtilizationCurve");
+    }
+
+    // Half of the target staked sits halfway between max and 1x
+    const { totalStaked } = await program.account.stakingPool.fetch(stakingPool);
+    await setCurve(totalStaked.muln(2), 5_000, 30_000);
+
+    let info = await program.methods.poolInfo().accounts({ stakingPool }).view();
+    expect(info.utilizationBps).to.equal(20_000);
+
+    const poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.utilizationStart.toNumber()).to.be.greaterThan(0);
+
+    // Turning the curve off restores 1x
+    await setCurve(new anchor.BN(0), 0, 0);
+    info = await program.methods.poolInfo().accounts({ stakingPool }).view();
+    expect(info.utilizationBps).to.equal(10_000);
+  });
+
+  it("Configures the loyalty bonus and tracks uninterrupted stake", async () => {
+    const setLoyalty = (rateBpsPerDay: number, capBps: number, emergencyDecayBps: number) =>
+      program.methods
+        .setLoyaltyConfig(rateBpsPerDay, capBps, emergencyDecayBps)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    try {
+      await setLoyalty(10, 5_000, 20_000);
+      expect.fail("Should have failed with InvalidLoyaltyConfig error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidLoyaltyConfig");
+    }
+
+    // +0.1% per day up to +25%, halved by an emergency unstake
+    await setLoyalty(10, 2_500, 5_000);
+
+    const poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.loyaltyRateBps).to.equal(10);
+    expect(poolAccount.loyaltyCapBps).to.equal(2_500);
+    expect(poolAccount.loyaltyDecayBps).to.equal(5_000);
+
+    // The loyalty clock runs only while the user has stake
+    const userAccountData = await program.account.userAccount.fetch(userAccount);
+    if (userAccountData.totalStaked.isZero()) {
+      expect(userAccountData.loyaltySince.toNumber()).to.equal(0);
+    } else {
+      expect(userAccountData.loyaltySince.toNumber()).to.be.greaterThan(0);
+    }
+
+    await setLoyalty(0, 0, 0);
+  });
+
+  it("Configures emergency penalties per lock tier", async () => {
+    const setPenalty = (
+      lockPeriod: object,
+      maxBps: number,
+      floorBps: number,
+      curve: object,
+      burnBps: number,
+      rewardsBps: number,
+    ) =>
+      program.methods
+        .setPenaltyConfig(lockPeriod, maxBps, floorBps, curve, burnBps, rewardsBps)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    let poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.penaltyConfigs[1].maxBps).to.equal(3_300);
+    expect(poolAccount.penaltyConfigs[1].burnBps).to.equal(4_000);
+
+    try {
+      await setPenalty({ oneWeek: {} }, 2_000, 2_500, { linear: {} }, 4_000, 4_000);
+      expect.fail("Should have failed with InvalidPenaltyConfig error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidPenaltyConfig");
+    }
+
+    try {
+      await setPenalty({ oneWeek: {} }, 2_000, 0, { linear: {} }, 6_000, 6_000);
+      expect.fail("Should have failed with InvalidPenaltyConfig error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidPenaltyConfig");
+    }
+
+    // 40% falling quadratically to a 5% floor, half burned and the rest to the treasury
+    await setPenalty({ oneWeek: {} }, 4_000, 500, { quadratic: {} }, 5_000, 0);
+
+    poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.penaltyConfigs[1].maxBps).to.equal(4_000);
+    expect(poolAccount.penaltyConfigs[1].floorBps).to.equal(500);
+    expect(poolAccount.penaltyConfigs[1].curve).to.equal(1);
+    expect(poolAccount.penaltyConfigs[1].rewardsBps).to.equal(0);
+    expect(poolAccount.penaltyConfigs[0].maxBps).to.equal(3_300);
+
+    await setPenalty({ oneWeek: {} }, 3_300, 0, { linear: {} }, 4_000, 4_000);
+  });
+
+  it("Configures the lock bonus forfeited on emergency unstake", async () => {
+    const setForfeit = (forfeitBps: number) =>
+      program.methods
+        .setBonusForfeit(forfeitBps)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    try {
+      await setForfeit(10_001);
+      expect.fail("Should have failed with InvalidBonusForfeit error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidBonusForfeit");
+    }
+
+    // Breaking a lock falls back to 1x rewards
+    await setForfeit(10_000);
+    const poolAccount = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAccount.bonusForfeitBps).to.equal(10_000);
+
+    await setForfeit(0);
+  });
+
+  it("Still forfeits the lock bonus when a claim comes before an emergency unstake", async () => {
+    const setForfeit = (forfeitBps: number) =>
+      program.methods
+        .setBonusForfeit(forfeitBps)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    await setForfeit(10_000);
+    await mintTo(
+      provider.connection,
+      authority.payer,
+      rewardMint,
+      rewardVault,
+      authority.publicKey,
+      1_000_000 * 10**9
+    );
+
+    const {
+      staker: claimant,
+      tokenAccount: claimantTokenAccount,
+      userAccount: claimantUserAccount,
+    } = await setupStaker(1000, { sixMonths: {} });
+    const claimantRewardAccount = await createAccount(
+      provider.connection,
+      authority.payer,
+      rewardMint,
+      claimant.publicKey
+    );
+
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    // Claiming pays out the 1x rewards and holds the lock bonus back on the entry
+    await program.methods
+      .claimRewards()
+      .accounts({
+        stakingPool,
+        userAccount: claimantUserAccount,
+        authority: claimant.publicKey,
+        userRewardAccount: claimantRewardAccount,
+        rewardVault,
+        rewardMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([claimant])
+      .rpc();
+
+    let userAccountData = await program.account.userAccount.fetch(claimantUserAccount);
+    const heldBonus = userAccountData.stakes[0].lockedBonus;
+    expect(heldBonus.toNumber()).to.be.greaterThan(0);
+    expect(userAccountData.pendingRewards.toNumber()).to.equal(0);
+
+    const signature = await program.methods
+      .emergencyUnstake(0)
+      .accounts({
+        stakingPool,
+        userAccount: claimantUserAccount,
+        authority: claimant.publicKey,
+        userTokenAccount: claimantTokenAccount,
+        stakingVault,
+        treasuryAccount,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([claimant])
+      .rpc({ commitment: "confirmed" });
+
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    const forfeited = [...parser.parseLogs(tx.meta.logMessages)].find(
+      (event) => event.name === "RewardsForfeitedEvent"
+    );
+    expect(forfeited).to.not.be.undefined;
+    expect(forfeited.data.amount.gte(heldBonus)).to.equal(true);
+
+    userAccountData = await program.account.userAccount.fetch(claimantUserAccount);
+    expect(userAccountData.stakes[0].lockedBonus.toNumber()).to.equal(0);
+
+    await setForfeit(0);
+  });
+
+  it("Exits a lock through the unlock cooldown with the full principal and lock bonus", async () => {
+    const {
+      staker: exitingStaker,
+      tokenAccount: exitingTokenAccount,
+      userAccount: exitingUserAccount,
+    } = await setupStaker(100, { oneWeek: {} });
+    const [unlockQueue] = PublicKey.findProgramAddressSync(
+      [Buffer.from(UNLOCK_QUEUE_SEED), exitingStaker.publicKey.toBuffer()],
+      program.programId
+    );
+
+    const setCooldown = (cooldown: number) =>
+      program.methods
+        .setUnlockCooldown(cooldown)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    const setForfeit = (forfeitBps: number) =>
+      program.methods
+        .setBonusForfeit(forfeitBps)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    const requestUnlock = () =>
+      program.methods
+        .requestUnlock(0)
+        .accounts({
+          stakingPool,
+          userAccount: exitingUserAccount,
+          unlockQueue,
+          authority: exitingStaker.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([exitingStaker])
+        .rpc({ commitment: "confirmed" });
+
+    const withdrawUnlocked = () =>
+      program.methods
+        .withdrawUnlocked()
+        .accounts({
+          stakingPool,
+          unlockQueue,
+          authority: exitingStaker.publicKey,
+          userTokenAccount: exitingTokenAccount,
+          stakingVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([exitingStaker])
+        .rpc();
+
+    try {
+      await setCooldown(200 * 86400);
+      expect.fail("Should have failed with InvalidUnlockCooldown error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidUnlockCooldown");
+    }
+
+    // Cooldown exits are off until the admin sets a cooldown
+    try {
+      await requestUnlock();
+      expect.fail("Should have failed with UnlockCooldownDisabled error");
+    } catch (error) {
+      expect(error.message).to.include("UnlockCooldownDisabled");
+    }
+
+    await setCooldown(2);
+    await setForfeit(10_000);
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    // Claiming pays the 1x share and leaves the lock bonus held back on the entry
+    await program.methods
+      .claimRewards()
+      .accounts({
+        stakingPool,
+        userAccount: exitingUserAccount,
+        authority: exitingStaker.publicKey,
+        userRewardAccount: await createAccount(provider.connection, authority.payer, rewardMint, exitingStaker.publicKey),
+        rewardVault,
+        rewardMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([exitingStaker])
+      .rpc();
+    const heldBonus = (await program.account.userAccount.fetch(exitingUserAccount)).stakes[0].lockedBonus;
+    expect(heldBonus.toNumber()).to.be.greaterThan(0);
+
+    const poolBefore = await program.account.stakingPool.fetch(stakingPool);
+    const signature = await requestUnlock();
+
+    // Nothing is forfeited: the lock bonus held back so far becomes claimable
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    const events = [...parser.parseLogs(tx.meta.logMessages)];
+    expect(events.find((event) => event.name === "RewardsForfeitedEvent")).to.be.undefined;
+    expect(events.find((event) => event.name === "UnlockRequestedEvent")).to.not.be.undefined;
+
+    const userAccountData = await program.account.userAccount.fetch(exitingUserAccount);
+    expect(userAccountData.stakes[0].isActive).to.equal(0);
+    expect(userAccountData.stakes[0].lockedBonus.toNumber()).to.equal(0);
+    expect(userAccountData.totalStaked.toNumber()).to.equal(0);
+    expect(userAccountData.pendingRewards.gte(heldBonus)).to.be.true;
+
+    const poolAfter = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolBefore.totalStaked.sub(poolAfter.totalStaked).toString()).to.equal((100n * 10n**9n).toString());
+
+    let queue = await program.account.unlockQueue.fetch(unlockQueue);
+    expect(queue.count).to.equal(1);
+    expect(queue.requests[0].amount.toString()).to.equal((100n * 10n**9n).toString());
+
+    try {
+      await withdrawUnlocked();
+      expect.fail("Should have failed with NothingToWithdraw error");
+    } catch (error) {
+      expect(error.message).to.include("NothingToWithdraw");
+    }
+
+    // Wait out the cooldown
+    await new Promise((resolve) => setTimeout(resolve, 4000));
+    await withdrawUnlocked();
+
+    const balance = await getAccount(provider.connection, exitingTokenAccount);
+    expect(balance.amount.toString()).to.equal((100n * 10n**9n).toString());
+    queue = await program.account.unlockQueue.fetch(unlockQueue);
+    expect(queue.count).to.equal(0);
+
+    await setCooldown(0);
+    await setForfeit(0);
+  });
+
+  it("Unstakes part of an entry while honoring the tier minimum", async () => {
+    const setPenalty = (maxBps: number, floorBps: number) =>
+      program.methods
+        .setPenaltyConfig({ oneWeek: {} }, maxBps, floorBps, { linear: {} }, 4_000, 4_000)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+        })
+        .rpc();
+
+    // Stake under a flat 20% 1-week penalty, then restore the default. The entry keeps
+    // the terms it was staked under, so the penalty is exact whenever it is charged.
+    await setPenalty(2_000, 2_000);
+    const {
+      staker: partialStaker,
+      tokenAccount: partialTokenAccount,
+      userAccount: partialUserAccount,
+    } = await setupStaker(600, { oneWeek: {} });
+    await setPenalty(3_300, 0);
+
+    // The entry is still locked
+    try {
+      await program.methods
+        .unstakePartial(0, new anchor.BN(100 * 10**9))
+        .accounts({
+          stakingPool,
+          userAccount: partialUserAccount,
+          authority: partialStaker.publicKey,
+          userTokenAccount: partialTokenAccount,
+          stakingVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([partialStaker])
+        .rpc();
+      expect.fail("Should have failed with StillLocked error");
+    } catch (error) {
+      expect(error.message).to.include("StillLocked");
+    }
+
+    const emergencyUnstakePartial = (amount: number) =>
+      program.methods
+        .emergencyUnstakePartial(0, new anchor.BN(amount * 10**9))
+        .accounts({
+          stakingPool,
+          userAccount: partialUserAccount,
+          authority: partialStaker.publicKey,
+          userTokenAccount: partialTokenAccount,
+          stakingVault,
+          treasuryAccount,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([partialStaker])
+        .rpc();
+
+    // 200 tokens left would be below the 250 token 1-week minimum
+    try {
+      await emergencyUnstakePartial(400);
+      expect.fail("Should have failed with BelowMinimumStake error");
+    } catch (error) {
+      expect(error.message).to.include("BelowMinimumStake");
+    }
+
+    const treasuryBefore = await getAccount(provider.connection, treasuryAccount);
+    const vaultBefore = await getAccount(provider.connection, stakingVault);
+    const rewardVaultBefore = await getAccount(provider.connection, rewardVault);
+    const supplyBefore = (await getMint(provider.connection, stakingMint)).supply;
+    const poolBefore = await program.account.stakingPool.fetch(stakingPool);
+    await emergencyUnstakePartial(100);
+
+    const userAccountData = await program.account.userAccount.fetch(partialUserAccount);
+    expect(userAccountData.stakes[0].isActive).to.equal(1);
+    expect(userAccountData.stakes[0].amount.toString()).to.equal((500n * 10n**9n).toString());
+    expect(userAccountData.totalStaked.toString()).to.equal((500n * 10n**9n).toString());
+
+    // The 20% penalty applies to the 100 tokens taken out only, and the treasury keeps
+    // what is left of it after the 40% burn and 40% rewards shares
+    const balance = await getAccount(provider.connection, partialTokenAccount);
+    expect(balance.amount.toString()).to.equal((80n * 10n**9n).toString());
+    const treasuryAfter = await getAccount(provider.connection, treasuryAccount);
+    expect((treasuryAfter.amount - treasuryBefore.amount).toString()).to.equal((4n * 10n**9n).toString());
+
+    // The 8 token burn share leaves the supply, and the 8 token rewards share stays in the
+    // vault counted in penalty_rewards. Rewards are paid in another mint, so the reward
+    // vault is untouched.
+    const vaultAfter = await getAccount(provider.connection, stakingVault);
+    expect((vaultBefore.amount - vaultAfter.amount).toString()).to.equal((92n * 10n**9n).toString());
+    const supplyAfter = (await getMint(provider.connection, stakingMint)).supply;
+    expect((supplyBefore - supplyAfter).toString()).to.equal((8n * 10n**9n).toString());
+    const rewardVaultAfter = await getAccount(provider.connection, rewardVault);
+    expect(rewardVaultAfter.amount.toString()).to.equal(rewardVaultBefore.amount.toString());
+    let poolAfter = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAfter.penaltyRewards.sub(poolBefore.penaltyRewards).toString()).to.equal((8n * 10n**9n).toString());
+
+    // The admin can move the rewards share out, but no more than it
+    const withdrawPenaltyRewards = (amount: anchor.BN) =>
+      program.methods
+        .withdrawPenaltyRewards(amount)
+        .accounts({
+          stakingPool,
+          authority: authority.publicKey,
+          stakingVault,
+          destination: treasuryAccount,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    try {
+      await withdrawPenaltyRewards(poolAfter.penaltyRewards.addn(1));
+      expect.fail("Should have failed with InvalidAmount error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidAmount");
+    }
+
+    await withdrawPenaltyRewards(poolAfter.penaltyRewards);
+    poolAfter = await program.account.stakingPool.fetch(stakingPool);
+    expect(poolAfter.penaltyRewards.toNumber()).to.equal(0);
+    const treasuryFinal = await getAccount(provider.connection, treasuryAccount);
+    expect((treasuryFinal.amount - treasuryAfter.amount).toString()).to.equal(
+      poolBefore.penaltyRewards.add(new anchor.BN((8n * 10n**9n).toString())).toString()
+    );
+  });
+
+  it("Opts a stake in and out of auto-renewal", async () => {
+    const { staker: renewingStaker, userAccount: renewingUserAccount } = await setupStaker(100, { oneDay: {} });
+
+    const setAutoRenew = (stakeIndex: number, enabled: boolean) =>
+      program.methods
+        .setAutoRenew(stakeIndex, enabled)
+        .accounts({
+          stakingPool,
+          userAccount: renewingUserAccount,
+          authority: renewingStaker.publicKey,
+        })
+        .signers([renewingStaker])
+        .rpc();
+
+    try {
+      await setAutoRenew(1, true);
+      expect.fail("Should have failed with InvalidStakeIndex error");
+    } catch (error) {
+      expect(error.message).to.include("InvalidStakeIndex");
+    }
+
+    await setAutoRenew(0, true);
+    let userAccountData = await program.account.userAccount.fetch(renewingUserAccount);
+    expect(userAccountData.autoRenew).to.equal(1);
+
+    // The lock is still running, so opting in leaves it unchanged
+    const { lockStart, lockEnd } = userAccountData.stakes[0];
+    expect(lockEnd.sub(lockStart).toNumber()).to.equal(86400);
+
+    await setAutoRenew(0, false);
+    userAccountData = await program.account.userAccount.fetch(renewingUserAccount);
+    expect(userAccountData.autoRenew).to.equal(0);
+    expect(userAccountData.stakes[0].lockEnd.toString()).to.equal(lockEnd.toString());
+  });
+
+  it("Batch unstakes only expired entries", async () => {
+    const {
+      staker: batchStaker,
+      tokenAccount: batchTokenAccount,
+      userAccount: batchUserAccount,
+    } = await setupStaker(200);
+    const batchRewardAccount = await createAccount(
+      provider.connection,
+      authority.payer,
+      rewardMint,
+      batchStaker.publicKey
+    );
+
+    const claimAndUnstakeAllExpired = () =>
+      program.methods
+        .claimAndUnstakeAllExpired()
+        .accounts({
+          stakingPool,
+          userAccount: batchUserAccount,
+          authority: batchStaker.publicKey,
+          userTokenAccount: batchTokenAccount,
+          stakingVault,
+          stakingMint,
+          userRewardAccount: batchRewardAccount,
+          rewardVault,
+          rewardMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([batchStaker])
+        .rpc();
+
+    await program.methods
+      .initializeUser()
+      .accounts({
+        userAccount: batchUserAccount,
+        authority: batchStaker.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([batchStaker])
+      .rpc();
+
+    // Nothing staked and nothing earned yet
+    try {
+      await claimAndUnstakeAllExpired();
+      expect.fail("Should have failed with NothingToClaimOrUnstake error");
+    } catch (error) {
+      expect(error.message).to.include("NothingToClaimOrUnstake");
+    }
+
+    for (let i = 0; i < 2; i++) {
+      await program.methods
+        .stake(new anchor.BN(100 * 10**9), { oneDay: {} })
+        .accounts({
+          stakingPool,
+          userAccount: batchUserAccount,
+          authority: batchStaker.publicKey,
+          userTokenAccount: batchTokenAccount,
+          stakingVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([batchStaker])
+        .rpc();
+    }
+
+    // Both entries are still locked
+    try {
+      await program.methods
+        .unstakeAllExpired()
+        .accounts({
+          stakingPool,
+          userAccount: batchUserAccount,
+          authority: batchStaker.publicKey,
+          userTokenAccount: batchTokenAccount,
+          stakingVault,
+          stakingMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([batchStaker])
+        .rpc();
+      expect.fail("Should have failed with NoExpiredStakes error");
+    } catch (error) {
+      expect(error.message).to.include("NoExpiredStakes");
+    }
+
+    // With nothing expired the combined instruction still pays out the rewards
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await claimAndUnstakeAllExpired();
+
+    const rewardBalance = await getAccount(provider.connection, batchRewardAccount);
+    expect(rewardBalance.amount > 0n).to.be.true;
+    const tokenBalance = await getAccount(provider.connection, batchTokenAccount);
+    expect(tokenBalance.amount.toString()).to.equal("0");
+
+    const userAccountData = await program.account.userAccount.fetch(batchUserAccount);
+    expect(userAccountData.stakes[0].isActive).to.equal(1);
+    expect(userAccountData.stakes[1].isActive).to.equal(1);
+    expect(userAccountData.totalStaked.toString()).to.equal((200n * 10n**9n).toString());
+  });
+
+  it("Benchmarks compute units for stake, unstake and claim", async () => {
+    // Set CU_BENCH_OUT to record results, and CU_BASELINE to compare against results
+    // recorded from another build (e.g. the Borsh layout before zero-copy)
+    const {
+      staker: benchUser,
+      tokenAccount: benchTokenAccount,
+      userAccount: benchUserAccount,
+    } = await setupStaker(100); // 100 tokens (minimum for 1-day)
+    const benchRewardAccount = await createAccount(
+      provider.connection,
+      authority.payer,
+      rewardMint,
+      benchUser.publicKey
+    );
+
+    await program.methods
+      .initializeUser()
+      .accounts({
+        userAccount: benchUserAccount,
+        authority: benchUser.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([benchUser])
+      .rpc();
+
+    const unitsConsumed = async (signature: string) => {
+      const tx = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      return tx.meta.computeUnitsConsumed;
+    };
+
+    const results: Record<string, number> = {};
+
+    const stakeSig = await program.methods
+      .stake(new anchor.BN(100 * 10**9), { oneDay: {} })
+      .accounts({
+        stakingPool,
+        userAccount: benchUserAccount,
+        authority: benchUser.publicKey,
+        userTokenAccount: benchTokenAccount,
+        stakingVault,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([benchUser])
+      .rpc({ commitment: "confirmed" });
+    results.stake = await unitsConsumed(stakeSig);
+
+    // Rewards accrue per whole day, so the claim is simulated and measured up to the
+    // NoRewardsToClaim check: account loading and the reward checkpoint, without the transfer
+    const claimTx = await program.methods
+      .claimRewards()
+      .accounts({
+        stakingPool,
+        userAccount: benchUserAccount,
+        authority: benchUser.publicKey,
+        userRewardAccount: benchRewardAccount,
+        rewardVault,
+        rewardMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .transaction();
+    claimTx.feePayer = benchUser.publicKey;
+    claimTx.recentBlockhash = (await provider.connection.getLatestBlockhash()).blockhash;
+    const claimSim = await provider.connection.simulateTransaction(claimTx, [benchUser]);
+    results.claimRewards = claimSim.value.unitsConsumed;
+
+    // The lock cannot expire within the test, so unstake is measured on the early exit path
+    const unstakeSig = await program.methods
+      .emergencyUnstake(0)
+      .accounts({
+        stakingPool,
+        userAccount: benchUserAccount,
+        authority: benchUser.publicKey,
+        userTokenAccount: benchTokenAccount,
+        stakingVault,
+        treasuryAccount,
+        stakingMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([benchUser])
+      .rpc({ commitment: "confirmed" });
+    results.emergencyUnstake = await unitsConsumed(unstakeSig);
+
+    for (const units of Object.values(results)) {
+      expect(units).to.be.greaterThan(0);
+    }
+
+    if (process.env.CU_BENCH_OUT) {
+      fs.writeFileSync(process.env.CU_BENCH_OUT, JSON.stringify(results, null, 2));
+    }
+
+    if (process.env.CU_BASELINE) {
+      const baseline: Record<string, number> = JSON.parse(fs.readFileSync(process.env.CU_BASELINE, "utf8"));
+      const comparison = Object.keys(results).map((instruction) => ({
+        instruction,
+        baseline: baseline[instruction],
+        current: results[instruction],
+        delta: results[instruction] - baseline[instruction],
+      }));
+      console.table(comparison);
+
+      for (const { instruction, baseline: before, current } of comparison) {
+        expect(current, instruction).to.be.at.most(before);
+      }
+    } else {
+      console.table(results);
+    }
+  });
+
+  it("Closes the user account and reclaims rent", async () => {
+    const rentBefore = await provider.connection.getBalance(authority.publicKey);
+
+    await program.methods
+      .closeUserAccount()
+      .accounts({
+        stakingPool,
+        userAccount,
+        authority: authority.publicKey,
+        userRewardAccount,
+        rewardVault,
+        rewardMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const closed = await provider.connection.getAccountInfo(userAccount);
+    expect(closed).to.be.null;
+
+    const rentAfter = await provider.connection.getBalance(authority.publicKey);
+    expect(rentAfter).to.be.greaterThan(rentBefore); // Rent refund outweighs the fee
   });
-});
\ No newline at end of file
+});
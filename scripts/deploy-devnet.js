@@ -154,7 +154,7 @@ async function deployProgram() {
 
     console.log("\n🔧 Program Instructions Available:");
     console.log("   • initialize_pool - Set up the staking pool");
-    console.log("   • initialize_user - Create user staking account");
+    console.log("   • initialize_user - Create user staking account (optional, the first stake creates it)");
     console.log("   • stake - Stake tokens with lock periods (1d, 1w, 3m, 6m)");
     console.log("   • unstake - Unstake after lock period expires");
     console.log("   • emergency_unstake - Unstake early with penalty");